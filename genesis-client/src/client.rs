@@ -0,0 +1,570 @@
+use crate::{
+    error::Error,
+    types::{Problem, Token, VerifyRequest, TOKEN_EXPIRATION},
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use futures_util::{stream, StreamExt};
+use reqwest::{header, Certificate, Identity, RequestBuilder, Response, StatusCode};
+use std::time::Duration;
+use tokio::time::sleep;
+use ulid::Ulid;
+use url::Url;
+use uuid::Uuid;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+// longest wait between two attempts, also caps Retry-After
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Client of the genesis API, cheap to clone
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    token_url: Url,
+    verify_url: Url,
+    retries: u32,
+    backoff: Duration,
+    concurrency: usize,
+}
+
+/// Options of a `Client`, from `Client::builder`
+#[derive(Debug, Clone)]
+pub struct Builder {
+    url: String,
+    verify_url: Option<String>,
+    timeout: Duration,
+    connect_timeout: Duration,
+    retries: u32,
+    backoff: Duration,
+    concurrency: usize,
+    identity: Option<Vec<u8>>,
+    root_certificates: Vec<Vec<u8>>,
+}
+
+impl Builder {
+    /// URL of the mTLS listener, `/v1/verify` is only served there when
+    /// genesis is configured with `--mtls-port`
+    #[must_use]
+    pub fn verify_url(mut self, url: impl Into<String>) -> Self {
+        self.verify_url = Some(url.into());
+        self
+    }
+
+    /// Timeout of each attempt, 5 seconds by default
+    #[must_use]
+    pub const fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// 2 seconds by default
+    #[must_use]
+    pub const fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Attempts after the first one when genesis can't be reached or answers
+    /// 502, 503 or 504, 2 by default
+    #[must_use]
+    pub const fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Wait before the first retry, doubled on each one, 100ms by default.
+    /// A `Retry-After` from genesis is used instead.
+    #[must_use]
+    pub const fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Tokens verified at the same time by `verify_batch`, 8 by default
+    #[must_use]
+    pub const fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Client certificate and key (PEM) for the mTLS listener
+    #[must_use]
+    pub fn identity(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.identity = Some(pem.into());
+        self
+    }
+
+    /// Trust this CA certificate (PEM), e.g. the one of the mTLS listener
+    #[must_use]
+    pub fn root_certificate(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.root_certificates.push(pem.into());
+        self
+    }
+
+    /// # Errors
+    /// `Error::Config` if a URL or a certificate is invalid
+    pub fn build(self) -> Result<Client, Error> {
+        let url = base_url(&self.url)?;
+        let verify_url = self
+            .verify_url
+            .as_deref()
+            .map_or_else(|| Ok(url.clone()), base_url)?;
+
+        let mut http = reqwest::Client::builder()
+            .user_agent(concat!(
+                env!("CARGO_PKG_NAME"),
+                "/",
+                env!("CARGO_PKG_VERSION")
+            ))
+            .timeout(self.timeout)
+            .connect_timeout(self.connect_timeout);
+
+        if let Some(pem) = &self.identity {
+            http = http.identity(
+                Identity::from_pem(pem)
+                    .map_err(|e| Error::Config(format!("client identity: {e}")))?,
+            );
+        }
+
+        for pem in &self.root_certificates {
+            http = http.add_root_certificate(
+                Certificate::from_pem(pem)
+                    .map_err(|e| Error::Config(format!("root certificate: {e}")))?,
+            );
+        }
+
+        Ok(Client {
+            http: http.build().map_err(|e| Error::Config(e.to_string()))?,
+            token_url: endpoint(&url, "token"),
+            verify_url: endpoint(&verify_url, "verify"),
+            retries: self.retries,
+            backoff: self.backoff,
+            concurrency: self.concurrency.max(1),
+        })
+    }
+}
+
+impl Client {
+    /// Options for a client of the genesis at `url`, e.g. `https://genesis.tld`
+    #[must_use]
+    pub fn builder(url: impl Into<String>) -> Builder {
+        Builder {
+            url: url.into(),
+            verify_url: None,
+            timeout: Duration::from_secs(5),
+            connect_timeout: Duration::from_secs(2),
+            retries: 2,
+            backoff: Duration::from_millis(100),
+            concurrency: 8,
+            identity: None,
+            root_certificates: Vec::new(),
+        }
+    }
+
+    /// Issue a token for the client
+    /// # Errors
+    /// `Error::Api` with a `BadRequest` problem for an unknown `client_id`
+    /// format, or any error of the request
+    pub async fn issue(&self, client_id: Uuid) -> Result<Token, Error> {
+        let response = self
+            .send(|| {
+                self.http
+                    .get(self.token_url.clone())
+                    .query(&[("client_id", client_id)])
+            })
+            .await?;
+
+        if response.status() == StatusCode::OK {
+            Ok(response.json().await?)
+        } else {
+            Err(error(response).await)
+        }
+    }
+
+    /// Verify a token, genesis records the first verification
+    /// # Errors
+    /// An error for which `is_rejected` is true when the token is not valid,
+    /// any error of the request otherwise
+    pub async fn verify(&self, token: &str) -> Result<(), Error> {
+        let body = VerifyRequest {
+            token: token.to_string(),
+        };
+
+        let response = self
+            .send(|| self.http.post(self.verify_url.clone()).json(&body))
+            .await?;
+
+        if response.status() == StatusCode::ACCEPTED {
+            Ok(())
+        } else {
+            Err(error(response).await)
+        }
+    }
+
+    /// Verify several tokens concurrently, the results are in the order of
+    /// the tokens
+    pub async fn verify_batch<I>(&self, tokens: I) -> Vec<Result<(), Error>>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        stream::iter(tokens)
+            .map(|token| async move { self.verify(token.as_ref()).await })
+            .buffered(self.concurrency)
+            .collect()
+            .await
+    }
+
+    // send the request, again on connection errors, timeouts and 502/503/504
+    async fn send(&self, request: impl Fn() -> RequestBuilder) -> Result<Response, Error> {
+        let mut attempt = 0;
+
+        loop {
+            let result = request().send().await;
+
+            let retry_after = match &result {
+                Ok(response) if retryable(response.status()) => retry_after(response),
+                Err(err) if err.is_connect() || err.is_timeout() => None,
+                _ => return Ok(result?),
+            };
+
+            if attempt >= self.retries {
+                return Ok(result?);
+            }
+
+            let backoff = self.backoff.saturating_mul(2u32.saturating_pow(attempt));
+
+            sleep(retry_after.unwrap_or(backoff).min(MAX_BACKOFF)).await;
+
+            attempt += 1;
+        }
+    }
+}
+
+/// What can be told about a token without calling genesis
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Introspection {
+    pub id: Ulid,
+    pub issued_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Introspection {
+    #[must_use]
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(Utc::now())
+    }
+
+    #[must_use]
+    pub fn is_expired_at(&self, now: DateTime<Utc>) -> bool {
+        now > self.expires_at
+    }
+}
+
+/// Decode a token, the ULID timestamp is the time it was issued.
+///
+/// Tokens are not signed, only genesis knows whether it issued one: use
+/// `Client::verify` to accept a request, this only avoids calling genesis
+/// for tokens that are malformed or expired.
+/// # Errors
+/// `Error::MalformedToken` if the token is not a ULID
+pub fn introspect(token: &str) -> Result<Introspection, Error> {
+    let id = Ulid::from_string(token)?;
+
+    let issued_at = DateTime::<Utc>::from(id.datetime());
+
+    Ok(Introspection {
+        id,
+        issued_at,
+        expires_at: issued_at + TimeDelta::seconds(TOKEN_EXPIRATION),
+    })
+}
+
+// the path of genesis can have a prefix, e.g. behind a proxy
+fn base_url(url: &str) -> Result<Url, Error> {
+    let url = Url::parse(url).map_err(|e| Error::Config(format!("{url}: {e}")))?;
+
+    if !matches!(url.scheme(), "http" | "https") || url.cannot_be_a_base() {
+        return Err(Error::Config(format!("{url}: expected an http(s) URL")));
+    }
+
+    Ok(url)
+}
+
+fn endpoint(base: &Url, name: &str) -> Url {
+    let mut url = base.clone();
+
+    // checked by base_url
+    if let Ok(mut segments) = url.path_segments_mut() {
+        segments.pop_if_empty().extend(["v1", name]);
+    }
+
+    url
+}
+
+const fn retryable(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+fn retry_after(response: &Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+// problem details when genesis sent them
+async fn error(response: Response) -> Error {
+    let status = response.status();
+
+    let is_problem = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(PROBLEM_JSON));
+
+    let body = match response.text().await {
+        Ok(body) => body,
+        Err(err) => return err.into(),
+    };
+
+    if is_problem {
+        if let Ok(problem) = serde_json::from_str::<Problem>(&body) {
+            return Error::Api(problem);
+        }
+    }
+
+    Error::UnexpectedResponse { status, body }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::types::ProblemKind;
+    use axum::{
+        extract::State,
+        http::HeaderValue,
+        response::{IntoResponse, Response as AxumResponse},
+        routing::{get, post},
+        Json, Router,
+    };
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    // genesis stand-in on a random port
+    pub(crate) async fn serve(app: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        url
+    }
+
+    pub(crate) fn problem(kind: ProblemKind, status: StatusCode) -> AxumResponse {
+        let body = serde_json::to_string(&Problem {
+            kind: kind.problem_type(),
+            title: "title".to_string(),
+            status: status.as_u16(),
+            detail: "detail".to_string(),
+            request_id: None,
+        })
+        .unwrap();
+
+        let mut response = (status, body).into_response();
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+        response
+    }
+
+    // tokens ending with Z were not issued
+    pub(crate) fn genesis() -> Router {
+        Router::new()
+            .route(
+                "/v1/token",
+                get(|| async {
+                    Json(Token {
+                        token: Ulid::new().to_string(),
+                        expires: 0,
+                    })
+                }),
+            )
+            .route(
+                "/v1/verify",
+                post(|Json(request): Json<VerifyRequest>| async move {
+                    if !request.token.ends_with('Z') {
+                        StatusCode::ACCEPTED.into_response()
+                    } else {
+                        problem(ProblemKind::InvalidToken, StatusCode::FORBIDDEN)
+                    }
+                }),
+            )
+    }
+
+    #[tokio::test]
+    async fn test_issue_and_verify() {
+        let client = Client::builder(serve(genesis()).await).build().unwrap();
+
+        let token = client.issue(Uuid::nil()).await.unwrap();
+        assert!(introspect(&token.token).is_ok());
+
+        client.verify("01HQ3S5B8D0RWPWWZ0XQNV6T6Y").await.unwrap();
+
+        let err = client
+            .verify("7ZZZZZZZZZZZZZZZZZZZZZZZZZ")
+            .await
+            .unwrap_err();
+        assert_eq!(err.problem_kind(), Some(ProblemKind::InvalidToken));
+        assert!(err.is_rejected());
+    }
+
+    #[tokio::test]
+    async fn test_verify_batch() {
+        let client = Client::builder(serve(genesis()).await)
+            .concurrency(2)
+            .build()
+            .unwrap();
+
+        let results = client
+            .verify_batch([
+                "01HQ3S5B8D0RWPWWZ0XQNV6T6Y",
+                "7ZZZZZZZZZZZZZZZZZZZZZZZZZ",
+                "0",
+            ])
+            .await;
+
+        assert!(results[0].is_ok());
+        assert!(results[1].as_ref().is_err_and(Error::is_rejected));
+        assert!(results[2].is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_retries() {
+        let attempts = Arc::new(AtomicU32::new(0));
+
+        // unavailable twice, then valid
+        let app = Router::new()
+            .route(
+                "/v1/verify",
+                post(|State(attempts): State<Arc<AtomicU32>>| async move {
+                    if attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                        let mut response =
+                            problem(ProblemKind::Unavailable, StatusCode::SERVICE_UNAVAILABLE);
+                        response
+                            .headers_mut()
+                            .insert(header::RETRY_AFTER, HeaderValue::from_static("0"));
+                        response
+                    } else {
+                        StatusCode::ACCEPTED.into_response()
+                    }
+                }),
+            )
+            .with_state(attempts.clone());
+
+        let url = serve(app).await;
+
+        let client = Client::builder(&url).build().unwrap();
+        client.verify("01HQ3S5B8D0RWPWWZ0XQNV6T6Y").await.unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        attempts.store(0, Ordering::SeqCst);
+
+        let client = Client::builder(&url).retries(1).build().unwrap();
+        let err = client
+            .verify("01HQ3S5B8D0RWPWWZ0XQNV6T6Y")
+            .await
+            .unwrap_err();
+        assert_eq!(err.problem_kind(), Some(ProblemKind::Unavailable));
+        assert!(err.is_unavailable());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let app = Router::new().route(
+            "/v1/verify",
+            post(|| async {
+                sleep(Duration::from_secs(5)).await;
+                StatusCode::ACCEPTED
+            }),
+        );
+
+        let client = Client::builder(serve(app).await)
+            .timeout(Duration::from_millis(50))
+            .retries(1)
+            .backoff(Duration::from_millis(1))
+            .build()
+            .unwrap();
+
+        let err = client
+            .verify("01HQ3S5B8D0RWPWWZ0XQNV6T6Y")
+            .await
+            .unwrap_err();
+        assert!(matches!(&err, Error::Http(err) if err.is_timeout()));
+        assert!(err.is_unavailable());
+        assert!(!err.is_rejected());
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_response() {
+        // a proxy without genesis behind
+        let app = Router::new().route(
+            "/v1/token",
+            get(|| async { (StatusCode::NOT_FOUND, "not found") }),
+        );
+
+        let client = Client::builder(serve(app).await).build().unwrap();
+
+        let err = client.issue(Uuid::nil()).await.unwrap_err();
+        assert!(
+            matches!(&err, Error::UnexpectedResponse { status, body } if *status == StatusCode::NOT_FOUND && body == "not found")
+        );
+        assert_eq!(err.problem_kind(), None);
+    }
+
+    #[test]
+    fn test_endpoint() {
+        let url = base_url("https://genesis.tld").unwrap();
+        assert_eq!(
+            endpoint(&url, "token").as_str(),
+            "https://genesis.tld/v1/token"
+        );
+
+        let url = base_url("https://proxy.tld/genesis/").unwrap();
+        assert_eq!(
+            endpoint(&url, "verify").as_str(),
+            "https://proxy.tld/genesis/v1/verify"
+        );
+
+        assert!(base_url("genesis.tld").is_err());
+        assert!(base_url("mailto:team@permesi.dev").is_err());
+    }
+
+    #[test]
+    fn test_introspect() {
+        let now = Utc::now();
+        let token = Ulid::from_datetime(now.into()).to_string();
+
+        let introspection = introspect(&token).unwrap();
+        assert_eq!(
+            (introspection.expires_at - introspection.issued_at).num_seconds(),
+            TOKEN_EXPIRATION
+        );
+        assert!(!introspection.is_expired_at(now));
+        assert!(introspection.is_expired_at(now + TimeDelta::seconds(TOKEN_EXPIRATION + 1)));
+
+        let err = introspect("nope").unwrap_err();
+        assert!(err.is_rejected());
+        assert!(!err.is_unavailable());
+    }
+}
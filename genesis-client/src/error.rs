@@ -0,0 +1,62 @@
+use crate::types::{Problem, ProblemKind};
+use reqwest::StatusCode;
+
+/// Errors of the client calls
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// genesis answered with a problem, e.g. the token expired
+    #[error("{} ({}): {}", .0.title, .0.status, .0.detail)]
+    Api(Problem),
+
+    /// an answer that is not a problem, e.g. from a proxy in front of genesis
+    #[error("unexpected response {status}: {body}")]
+    UnexpectedResponse { status: StatusCode, body: String },
+
+    /// the request failed or timed out, after the retries
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+
+    /// the token is not a ULID, found without calling genesis
+    #[error("malformed token: {0}")]
+    MalformedToken(#[from] ulid::DecodeError),
+
+    /// invalid URL or TLS certificates
+    #[error("invalid client configuration: {0}")]
+    Config(String),
+}
+
+impl Error {
+    /// The kind of the problem genesis answered with
+    #[must_use]
+    pub fn problem_kind(&self) -> Option<ProblemKind> {
+        match self {
+            Self::Api(problem) => problem.problem_kind(),
+            Self::MalformedToken(_) => Some(ProblemKind::MalformedToken),
+            _ => None,
+        }
+    }
+
+    /// The token is not valid: malformed, expired or not issued by genesis.
+    /// Retrying won't help, the request with the token should be refused.
+    #[must_use]
+    pub fn is_rejected(&self) -> bool {
+        matches!(
+            self.problem_kind(),
+            Some(
+                ProblemKind::MalformedToken | ProblemKind::TokenExpired | ProblemKind::InvalidToken
+            )
+        )
+    }
+
+    /// genesis could not be reached or could not answer, the token may be
+    /// valid
+    #[must_use]
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            Self::Http(_) => true,
+            Self::Api(problem) => problem.status >= 500,
+            Self::UnexpectedResponse { status, .. } => status.is_server_error(),
+            Self::MalformedToken(_) | Self::Config(_) => false,
+        }
+    }
+}
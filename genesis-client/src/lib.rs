@@ -0,0 +1,38 @@
+//! Client for the genesis Token Zero API
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), permesi_genesis_client::Error> {
+//! use permesi_genesis_client::Client;
+//! use uuid::Uuid;
+//!
+//! let client = Client::builder("https://genesis.permesi.dev").build()?;
+//!
+//! let token = client.issue(Uuid::nil()).await?;
+//!
+//! match client.verify(&token.token).await {
+//!     Ok(()) => println!("valid"),
+//!     Err(err) if err.is_rejected() => println!("rejected: {err}"),
+//!     Err(err) => return Err(err),
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The request and response types are shared with the server.
+
+mod client;
+mod error;
+#[cfg(feature = "axum")]
+mod middleware;
+mod types;
+
+pub use client::{introspect, Builder, Client, Introspection};
+pub use error::Error;
+pub use types::{
+    Problem, ProblemKind, Token, VerifyRequest, PROBLEM_TYPE_PREFIX, TOKEN_EXPIRATION,
+};
+
+#[cfg(feature = "axum")]
+pub use middleware::{
+    TokenSource, TokenZero, TokenZeroLayer, TokenZeroService, Verifier, DEFAULT_HEADER,
+};
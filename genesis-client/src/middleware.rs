@@ -0,0 +1,648 @@
+use crate::{
+    client::{introspect, Client, PROBLEM_JSON},
+    error::Error,
+    types::{Problem, ProblemKind},
+};
+use axum::{
+    async_trait,
+    body::{self, Body},
+    extract::{FromRequestParts, Request},
+    http::{header, request::Parts, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use futures_util::future::BoxFuture;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower::{Layer, Service};
+use tracing::{debug, warn};
+
+/// Header the token is read from by default
+pub const DEFAULT_HEADER: &str = "x-token-zero";
+
+// rejected tokens remembered at most, the expired entries are dropped first
+const NEGATIVE_CACHE_CAPACITY: usize = 10_000;
+
+/// Where the token is in the request
+#[derive(Debug, Clone)]
+pub enum TokenSource {
+    Header(HeaderName),
+    /// top level field of a JSON body, the body is buffered and passed on
+    JsonField(String),
+}
+
+/// Token Zero of the request, verified by genesis
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenZero {
+    pub token: String,
+    pub issued_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// false when genesis could not be reached and the verifier fails open
+    pub verified: bool,
+}
+
+/// Verify the Token Zero of the requests, used by `TokenZeroLayer` and by the
+/// `TokenZero` extractor when added as an `Extension`
+#[derive(Debug, Clone)]
+pub struct Verifier {
+    client: Client,
+    source: TokenSource,
+    fail_open: bool,
+    negative_ttl: Duration,
+    body_limit: usize,
+    // rejected tokens and when to forget them, shared by the clones
+    rejected: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl Verifier {
+    /// Read the token from the `x-token-zero` header, fail closed and
+    /// remember rejected tokens for 30 seconds
+    #[must_use]
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            source: TokenSource::Header(HeaderName::from_static(DEFAULT_HEADER)),
+            fail_open: false,
+            negative_ttl: Duration::from_secs(30),
+            body_limit: 64 * 1024,
+            rejected: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    #[must_use]
+    pub fn header(mut self, name: HeaderName) -> Self {
+        self.source = TokenSource::Header(name);
+        self
+    }
+
+    /// Read the token from a field of the JSON body, only with the layer
+    #[must_use]
+    pub fn json_field(mut self, field: impl Into<String>) -> Self {
+        self.source = TokenSource::JsonField(field.into());
+        self
+    }
+
+    /// Let the requests through, with an unverified `TokenZero`, when genesis
+    /// can't be reached or answers with a 5xx. Closed by default: they get a
+    /// 503. Other errors are always refused with a 502.
+    #[must_use]
+    pub const fn fail_open(mut self, fail_open: bool) -> Self {
+        self.fail_open = fail_open;
+        self
+    }
+
+    /// How long a rejected token is refused without asking genesis again,
+    /// zero disables the cache
+    #[must_use]
+    pub const fn negative_cache(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = ttl;
+        self
+    }
+
+    /// Largest JSON body read to find the token, 64 KiB by default
+    #[must_use]
+    pub const fn body_limit(mut self, limit: usize) -> Self {
+        self.body_limit = limit;
+        self
+    }
+
+    #[must_use]
+    pub const fn layer(self) -> TokenZeroLayer {
+        TokenZeroLayer { verifier: self }
+    }
+
+    /// Verify a token, the error is the response for the client
+    /// # Errors
+    /// The problem response when the token is rejected or genesis can't be
+    /// reached and the verifier fails closed
+    pub async fn verify(&self, token: &str) -> Result<TokenZero, Response> {
+        // malformed and expired tokens are refused without calling genesis
+        let introspection = introspect(token).map_err(|err| rejection(&err))?;
+
+        if introspection.is_expired() {
+            return Err(problem(
+                ProblemKind::TokenExpired,
+                StatusCode::FORBIDDEN,
+                "The token is too old to be verified",
+            ));
+        }
+
+        if self.is_rejected(token) {
+            debug!("Token Zero rejected recently");
+
+            return Err(problem(
+                ProblemKind::InvalidToken,
+                StatusCode::FORBIDDEN,
+                "The token was not issued by genesis",
+            ));
+        }
+
+        let verified = match self.client.verify(token).await {
+            Ok(()) => true,
+            Err(err) if err.is_rejected() => {
+                self.remember_rejected(token);
+
+                return Err(rejection(&err));
+            }
+            Err(err) if err.is_unavailable() && self.fail_open => {
+                warn!(
+                    "Failed to verify Token Zero, letting the request through: {}",
+                    err
+                );
+
+                false
+            }
+            Err(err) if err.is_unavailable() => {
+                warn!("Failed to verify Token Zero: {}", err);
+
+                return Err(problem(
+                    ProblemKind::Unavailable,
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "The token can't be verified, try again later",
+                ));
+            }
+            // genesis answered but not as expected, e.g. a wrong URL or
+            // credentials, refused even when failing open
+            Err(err) => {
+                warn!("Failed to verify Token Zero: {}", err);
+
+                return Err(problem(
+                    ProblemKind::Internal,
+                    StatusCode::BAD_GATEWAY,
+                    "The token can't be verified",
+                ));
+            }
+        };
+
+        Ok(TokenZero {
+            token: token.to_string(),
+            issued_at: introspection.issued_at,
+            expires_at: introspection.expires_at,
+            verified,
+        })
+    }
+
+    // verify the token of the request and add the `TokenZero` to it
+    async fn verify_request(&self, request: Request) -> Result<Request, Response> {
+        let (mut parts, body) = request.into_parts();
+
+        let (token, body) = match &self.source {
+            TokenSource::Header(name) => (header_token(&parts, name)?, body),
+
+            TokenSource::JsonField(field) => {
+                let bytes = body::to_bytes(body, self.body_limit).await.map_err(|_| {
+                    problem(
+                        ProblemKind::BadRequest,
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "The body is too large",
+                    )
+                })?;
+
+                let token = serde_json::from_slice::<serde_json::Value>(&bytes)
+                    .ok()
+                    .and_then(|json| json.get(field)?.as_str().map(ToString::to_string))
+                    .ok_or_else(|| missing(&format!("the {field} field of the JSON body")))?;
+
+                (token, Body::from(bytes))
+            }
+        };
+
+        let token_zero = self.verify(&token).await?;
+
+        parts.extensions.insert(token_zero);
+
+        Ok(Request::from_parts(parts, body))
+    }
+
+    fn is_rejected(&self, token: &str) -> bool {
+        let rejected = self.rejected.lock().unwrap_or_else(PoisonError::into_inner);
+
+        rejected
+            .get(token)
+            .is_some_and(|until| *until > Instant::now())
+    }
+
+    fn remember_rejected(&self, token: &str) {
+        if self.negative_ttl.is_zero() {
+            return;
+        }
+
+        let mut rejected = self.rejected.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let now = Instant::now();
+
+        if rejected.len() >= NEGATIVE_CACHE_CAPACITY {
+            rejected.retain(|_, until| *until > now);
+        }
+
+        // still full of live entries, start over rather than grow
+        if rejected.len() >= NEGATIVE_CACHE_CAPACITY {
+            rejected.clear();
+        }
+
+        rejected.insert(token.to_string(), now + self.negative_ttl);
+    }
+}
+
+/// Refuse the requests without a valid Token Zero, the handlers get the
+/// verified `TokenZero` with the extractor
+#[derive(Debug, Clone)]
+pub struct TokenZeroLayer {
+    verifier: Verifier,
+}
+
+impl<S> Layer<S> for TokenZeroLayer {
+    type Service = TokenZeroService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TokenZeroService {
+            inner,
+            verifier: self.verifier.clone(),
+        }
+    }
+}
+
+/// Service of `TokenZeroLayer`
+#[derive(Debug, Clone)]
+pub struct TokenZeroService<S> {
+    inner: S,
+    verifier: Verifier,
+}
+
+impl<S> Service<Request> for TokenZeroService<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let verifier = self.verifier.clone();
+
+        // the ready service handles this request, the clone the next one
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            match verifier.verify_request(request).await {
+                Ok(request) => inner.call(request).await,
+                Err(response) => Ok(response),
+            }
+        })
+    }
+}
+
+/// The `TokenZero` verified by `TokenZeroLayer`, or verified from the header
+/// by a `Verifier` added as an `Extension`
+#[async_trait]
+impl<S> FromRequestParts<S> for TokenZero
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(token_zero) = parts.extensions.get::<Self>() {
+            return Ok(token_zero.clone());
+        }
+
+        let Some(verifier) = parts.extensions.get::<Verifier>().cloned() else {
+            return Err(internal(
+                "TokenZero needs the TokenZeroLayer or a Verifier extension",
+            ));
+        };
+
+        let TokenSource::Header(name) = &verifier.source else {
+            return Err(internal(
+                "TokenZero from a JSON body needs the TokenZeroLayer",
+            ));
+        };
+
+        let token = header_token(parts, name)?;
+
+        let token_zero = verifier.verify(&token).await?;
+
+        parts.extensions.insert(token_zero.clone());
+
+        Ok(token_zero)
+    }
+}
+
+// the error is the response, as the rejections of the extractors
+#[allow(clippy::result_large_err)]
+fn header_token(parts: &Parts, name: &HeaderName) -> Result<String, Response> {
+    parts
+        .headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .ok_or_else(|| missing(&format!("the {name} header")))
+}
+
+fn missing(place: &str) -> Response {
+    problem(
+        ProblemKind::BadRequest,
+        StatusCode::BAD_REQUEST,
+        &format!("Missing Token Zero in {place}"),
+    )
+}
+
+fn internal(detail: &str) -> Response {
+    warn!("{}", detail);
+
+    problem(
+        ProblemKind::Internal,
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "The request failed, try again later",
+    )
+}
+
+// the problem genesis answered with, malformed tokens are found locally
+fn rejection(err: &Error) -> Response {
+    match err {
+        Error::Api(problem) => problem_response(problem),
+        _ => problem(
+            ProblemKind::MalformedToken,
+            StatusCode::BAD_REQUEST,
+            "The token is not a valid ULID",
+        ),
+    }
+}
+
+fn problem(kind: ProblemKind, status: StatusCode, detail: &str) -> Response {
+    problem_response(&Problem {
+        kind: kind.problem_type(),
+        title: status.canonical_reason().unwrap_or_default().to_string(),
+        status: status.as_u16(),
+        detail: detail.to_string(),
+        request_id: None,
+    })
+}
+
+fn problem_response(problem: &Problem) -> Response {
+    let status = StatusCode::from_u16(problem.status).unwrap_or(StatusCode::FORBIDDEN);
+
+    let mut response = (status, serde_json::to_vec(problem).unwrap_or_default()).into_response();
+
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::tests::{genesis, serve};
+    use axum::{
+        extract::State,
+        http::{Request as HttpRequest, StatusCode},
+        routing::post,
+        Extension, Json, Router,
+    };
+    use chrono::Utc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tower::ServiceExt;
+    use ulid::Ulid;
+
+    // issued now, accepted by the stub
+    fn valid() -> String {
+        Ulid::from_parts(now_ms(), 0).to_string()
+    }
+
+    // issued now, ends with Z so the stub rejects it
+    fn invalid() -> String {
+        Ulid::from_parts(now_ms(), u128::MAX >> 48).to_string()
+    }
+
+    fn now_ms() -> u64 {
+        u64::try_from(Utc::now().timestamp_millis()).unwrap()
+    }
+
+    async fn verifier() -> Verifier {
+        Verifier::new(Client::builder(serve(genesis()).await).build().unwrap())
+    }
+
+    // genesis down, no retries to keep the tests fast
+    fn unreachable() -> Verifier {
+        Verifier::new(
+            Client::builder("http://127.0.0.1:1")
+                .retries(0)
+                .build()
+                .unwrap(),
+        )
+    }
+
+    fn app(verifier: Verifier) -> Router {
+        Router::new()
+            .route(
+                "/",
+                post(|token_zero: TokenZero| async move { token_zero.verified.to_string() }),
+            )
+            .layer(verifier.layer())
+    }
+
+    async fn call(app: Router, token: Option<&str>) -> (StatusCode, String) {
+        let mut request = HttpRequest::post("/");
+        if let Some(token) = token {
+            request = request.header(DEFAULT_HEADER, token);
+        }
+
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        let status = response.status();
+        let body = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn kind(body: &str) -> Option<ProblemKind> {
+        serde_json::from_str::<Problem>(body).ok()?.problem_kind()
+    }
+
+    #[tokio::test]
+    async fn test_header() {
+        let app = app(verifier().await);
+
+        let (status, body) = call(app.clone(), Some(&valid())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "true");
+
+        let (status, body) = call(app.clone(), Some(&invalid())).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(kind(&body), Some(ProblemKind::InvalidToken));
+
+        let (status, body) = call(app.clone(), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(kind(&body), Some(ProblemKind::BadRequest));
+
+        let (status, body) = call(app, Some("not-a-ulid")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(kind(&body), Some(ProblemKind::MalformedToken));
+    }
+
+    #[tokio::test]
+    async fn test_expired_without_calling_genesis() {
+        let (status, body) = call(app(unreachable()), Some("01HQ3S5B8D0RWPWWZ0XQNV6T6Y")).await;
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(kind(&body), Some(ProblemKind::TokenExpired));
+    }
+
+    #[tokio::test]
+    async fn test_negative_cache() {
+        let calls = Arc::new(AtomicU32::new(0));
+
+        let stub = Router::new()
+            .merge(genesis())
+            .layer(axum::middleware::from_fn_with_state(
+                calls.clone(),
+                |State(calls): State<Arc<AtomicU32>>,
+                 request: Request,
+                 next: axum::middleware::Next| async move {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    next.run(request).await
+                },
+            ));
+
+        let client = Client::builder(serve(stub).await).build().unwrap();
+        let token = invalid();
+
+        let app = app(Verifier::new(client.clone()));
+        for _ in 0..3 {
+            assert_eq!(
+                call(app.clone(), Some(&token)).await.0,
+                StatusCode::FORBIDDEN
+            );
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // without the cache every request asks genesis
+        let app = self::app(Verifier::new(client).negative_cache(Duration::ZERO));
+        for _ in 0..2 {
+            assert_eq!(
+                call(app.clone(), Some(&token)).await.0,
+                StatusCode::FORBIDDEN
+            );
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_fail_closed() {
+        let (status, body) = call(app(unreachable()), Some(&valid())).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(kind(&body), Some(ProblemKind::Unavailable));
+    }
+
+    #[tokio::test]
+    async fn test_fail_open() {
+        let app = app(unreachable().fail_open(true));
+
+        let (status, body) = call(app.clone(), Some(&valid())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "false");
+
+        // only genesis being down is let through
+        let (status, _) = call(app, Some("not-a-ulid")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_fail_open_not_found() {
+        // genesis behind the wrong URL, not down
+        let stub = Router::new().fallback(|| async { StatusCode::NOT_FOUND });
+        let client = Client::builder(serve(stub).await)
+            .retries(0)
+            .build()
+            .unwrap();
+
+        for fail_open in [false, true] {
+            let app = app(Verifier::new(client.clone()).fail_open(fail_open));
+
+            let (status, body) = call(app, Some(&valid())).await;
+            assert_eq!(status, StatusCode::BAD_GATEWAY);
+            assert_eq!(kind(&body), Some(ProblemKind::Internal));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json_field() {
+        let app = Router::new()
+            .route(
+                "/",
+                post(
+                    |token_zero: TokenZero, Json(body): Json<serde_json::Value>| async move {
+                        assert_eq!(body["token"], token_zero.token.as_str());
+                        body["name"].as_str().unwrap_or_default().to_string()
+                    },
+                ),
+            )
+            .layer(verifier().await.json_field("token").layer());
+
+        let request = |body: String| {
+            HttpRequest::post("/")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request(format!(
+                r#"{{"token":"{}","name":"genesis"}}"#,
+                valid()
+            )))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"genesis");
+
+        let response = app
+            .oneshot(request(r#"{"name":"genesis"}"#.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_extractor_with_extension() {
+        let app = Router::new()
+            .route(
+                "/",
+                post(|token_zero: TokenZero| async move { token_zero.verified.to_string() }),
+            )
+            .layer(Extension(verifier().await));
+
+        assert_eq!(call(app.clone(), Some(&valid())).await.0, StatusCode::OK);
+        assert_eq!(call(app, Some(&invalid())).await.0, StatusCode::FORBIDDEN);
+
+        // neither the layer nor the extension
+        let app = Router::new().route("/", post(|_: TokenZero| async {}));
+        assert_eq!(
+            call(app, Some(&valid())).await.0,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
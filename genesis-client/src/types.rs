@@ -0,0 +1,137 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Seconds a token can be verified after it was issued
+pub const TOKEN_EXPIRATION: i64 = 120; // 2 minutes
+
+/// Prefix of the problem types, followed by the slug of a `ProblemKind`
+pub const PROBLEM_TYPE_PREFIX: &str = "urn:permesi:genesis:";
+
+/// Token issued by `/v1/token`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Token {
+    pub token: String,
+    pub expires: i64,
+}
+
+impl Token {
+    /// When the token stops being accepted by `/v1/verify`
+    #[must_use]
+    pub const fn expires_at(&self) -> Option<DateTime<Utc>> {
+        DateTime::from_timestamp(self.expires, 0)
+    }
+}
+
+/// Body of `/v1/verify`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct VerifyRequest {
+    pub token: String,
+}
+
+/// RFC 7807 problem details
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Problem {
+    // stable identifier of the error, e.g. urn:permesi:genesis:token-expired
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    // x-request-id of the request, to find it in the logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl Problem {
+    /// The kind of the problem, `None` for a type this version doesn't know
+    #[must_use]
+    pub fn problem_kind(&self) -> Option<ProblemKind> {
+        ProblemKind::from_type(&self.kind)
+    }
+}
+
+/// The problem types genesis returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProblemKind {
+    // missing or invalid parameter or body
+    BadRequest,
+    // the token is not a ULID
+    MalformedToken,
+    // issued more than TOKEN_EXPIRATION seconds ago
+    TokenExpired,
+    // not issued by genesis
+    InvalidToken,
+    // not available in this deployment, e.g. analytics with the in-memory store
+    NotImplemented,
+    // the database credentials are being renewed
+    Unavailable,
+    Internal,
+}
+
+impl ProblemKind {
+    const ALL: [Self; 7] = [
+        Self::BadRequest,
+        Self::MalformedToken,
+        Self::TokenExpired,
+        Self::InvalidToken,
+        Self::NotImplemented,
+        Self::Unavailable,
+        Self::Internal,
+    ];
+
+    /// Last part of the problem type, part of the API, never change it
+    #[must_use]
+    pub const fn slug(self) -> &'static str {
+        match self {
+            Self::BadRequest => "bad-request",
+            Self::MalformedToken => "malformed-token",
+            Self::TokenExpired => "token-expired",
+            Self::InvalidToken => "invalid-token",
+            Self::NotImplemented => "not-implemented",
+            Self::Unavailable => "unavailable",
+            Self::Internal => "internal",
+        }
+    }
+
+    /// The problem type, e.g. `urn:permesi:genesis:token-expired`
+    #[must_use]
+    pub fn problem_type(self) -> String {
+        format!("{PROBLEM_TYPE_PREFIX}{}", self.slug())
+    }
+
+    #[must_use]
+    pub fn from_type(problem_type: &str) -> Option<Self> {
+        let slug = problem_type.strip_prefix(PROBLEM_TYPE_PREFIX)?;
+
+        Self::ALL.into_iter().find(|kind| kind.slug() == slug)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_problem_kind() {
+        for kind in ProblemKind::ALL {
+            assert_eq!(ProblemKind::from_type(&kind.problem_type()), Some(kind));
+        }
+
+        assert_eq!(ProblemKind::from_type("token-expired"), None);
+        assert_eq!(ProblemKind::from_type("urn:permesi:genesis:new"), None);
+    }
+
+    #[test]
+    fn test_problem_without_request_id() {
+        let problem: Problem = serde_json::from_str(
+            r#"{"type":"urn:permesi:genesis:invalid-token","title":"Invalid token","status":403,"detail":"The token was not issued by genesis"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(problem.problem_kind(), Some(ProblemKind::InvalidToken));
+        assert_eq!(problem.request_id, None);
+    }
+}
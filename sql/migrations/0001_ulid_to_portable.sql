@@ -0,0 +1,31 @@
+-- Migrate the pgx_ulid schema (schema.sql) to the portable one (schema-portable.sql)
+-- psql -U <user> -d genesis -f migrations/0001_ulid_to_portable.sql
+--
+-- The ULIDs keep the same 128 bits stored as uuid and the issue time is
+-- copied from the ULID timestamp to issued_at. Stop genesis (or accept failed
+-- requests while it runs) and restart it with --schema-layout portable.
+
+BEGIN;
+
+ALTER TABLE metadata DROP CONSTRAINT IF EXISTS metadata_id_fkey;
+
+ALTER TABLE tokens ADD COLUMN issued_at timestamptz;
+UPDATE tokens SET issued_at = id::timestamp AT TIME ZONE 'UTC';
+ALTER TABLE tokens ALTER COLUMN issued_at SET NOT NULL;
+
+ALTER TABLE tokens ALTER COLUMN id DROP DEFAULT;
+ALTER TABLE tokens ALTER COLUMN id TYPE uuid USING id::uuid;
+ALTER TABLE metadata ALTER COLUMN id TYPE uuid USING id::uuid;
+
+ALTER TABLE metadata
+    ADD CONSTRAINT metadata_id_fkey FOREIGN KEY (id) REFERENCES tokens(id) ON DELETE CASCADE;
+
+CREATE INDEX IF NOT EXISTS idx_tokens_issued_at ON tokens(issued_at);
+
+ALTER TABLE clients ALTER COLUMN uuid SET DEFAULT gen_random_uuid();
+
+COMMIT;
+
+-- Once nothing else uses them:
+-- DROP EXTENSION ulid;
+-- DROP EXTENSION "uuid-ossp";
@@ -0,0 +1,8 @@
+-- Record when tokens are verified, used by the /admin/stats endpoints
+-- psql -U <user> -d genesis -f migrations/0002_verified_at.sql
+--
+-- Works with every layout (ulid, portable and partitioned), run it before
+-- starting genesis with --track-verified, the verifications are not recorded
+-- without the column.
+
+ALTER TABLE tokens ADD COLUMN IF NOT EXISTS verified_at timestamptz;
@@ -0,0 +1,7 @@
+-- CORS origins of each client, used by /token
+-- psql -U <user> -d genesis -f migrations/0003_clients_allowed_origins.sql
+--
+-- Works with every layout (ulid, portable and partitioned), run it before
+-- upgrading genesis, the client lookup fails without the column.
+
+ALTER TABLE clients ADD COLUMN IF NOT EXISTS allowed_origins text[];
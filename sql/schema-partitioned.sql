@@ -0,0 +1,58 @@
+-- psql -U <user> -d genesis -f schema-partitioned.sql
+-- genesis --schema-layout partitioned
+--
+-- Same columns as schema-portable.sql but tokens and metadata are partitioned
+-- by issued_at, genesis creates the partitions ahead of time and drops the old
+-- ones (--partition-interval, --partition-premake, --partition-retention) so
+-- expired tokens don't need to be deleted row by row
+
+-- Create the table for clients
+DROP TABLE IF EXISTS clients CASCADE;
+CREATE TABLE clients (
+    id SMALLINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    name text NOT NULL,
+    uuid UUID DEFAULT gen_random_uuid() UNIQUE,
+    -- CORS origins allowed to request tokens for this client, NULL uses the global ones
+    allowed_origins text[]
+);
+
+INSERT INTO clients (id, name, uuid)
+OVERRIDING SYSTEM VALUE
+VALUES (0, 'unknown', '00000000-0000-0000-0000-000000000000');
+
+-- Create the table for the tokens, the partition key must be part of the
+-- primary key
+DROP TABLE IF EXISTS tokens CASCADE;
+CREATE TABLE tokens (
+    id uuid NOT NULL,
+    client_id SMALLINT NOT NULL REFERENCES clients(id) ON DELETE CASCADE,
+    issued_at timestamptz NOT NULL,
+    -- first successful /verify, NULL if never verified
+    verified_at timestamptz,
+    PRIMARY KEY (id, issued_at)
+) PARTITION BY RANGE (issued_at);
+
+-- Create the table for the metadata, partitioned the same way so a partition
+-- of tokens and its metadata are dropped together
+DROP TABLE IF EXISTS metadata;
+CREATE TABLE metadata (
+    id uuid NOT NULL,
+    issued_at timestamptz NOT NULL,
+    ip_address INET,
+    country CHAR(2),
+    user_agent text,
+    PRIMARY KEY (id, issued_at),
+    FOREIGN KEY (id, issued_at) REFERENCES tokens(id, issued_at) ON DELETE CASCADE
+) PARTITION BY RANGE (issued_at);
+
+CREATE INDEX idx_metadata_country ON metadata(country);
+CREATE INDEX idx_metadata_ip ON metadata(ip_address);
+
+-- When genesis connects with Vault dynamic credentials every user is
+-- different, create a role that owns the partitions and grant it to the
+-- Vault role, then start genesis with --partition-owner-role genesis_owner
+--
+-- CREATE ROLE genesis_owner NOLOGIN;
+-- ALTER TABLE tokens OWNER TO genesis_owner;
+-- ALTER TABLE metadata OWNER TO genesis_owner;
+-- GRANT genesis_owner TO <vault role>;
@@ -0,0 +1,43 @@
+-- psql -U <user> -d genesis -f schema-portable.sql
+-- genesis --schema-layout portable
+--
+-- No extensions needed (RDS, Cloud SQL), the ULID is stored as uuid (same
+-- 128 bits) and the time it was issued in issued_at
+
+-- Create the table for clients
+DROP TABLE IF EXISTS clients CASCADE;
+CREATE TABLE clients (
+    id SMALLINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    name text NOT NULL,
+    uuid UUID DEFAULT gen_random_uuid() UNIQUE,
+    -- CORS origins allowed to request tokens for this client, NULL uses the global ones
+    allowed_origins text[]
+);
+
+INSERT INTO clients (id, name, uuid)
+OVERRIDING SYSTEM VALUE
+VALUES (0, 'unknown', '00000000-0000-0000-0000-000000000000');
+
+-- Create the table for the tokens
+DROP TABLE IF EXISTS tokens CASCADE;
+CREATE TABLE tokens (
+    id uuid PRIMARY KEY,
+    client_id SMALLINT NOT NULL REFERENCES clients(id) ON DELETE CASCADE,
+    issued_at timestamptz NOT NULL,
+    -- first successful /verify, NULL if never verified
+    verified_at timestamptz
+);
+
+CREATE INDEX idx_tokens_issued_at ON tokens(issued_at);
+
+-- Create the table for the metadata
+DROP TABLE IF EXISTS metadata;
+CREATE TABLE metadata (
+    id uuid PRIMARY KEY REFERENCES tokens(id) ON DELETE CASCADE,
+    ip_address INET,
+    country CHAR(2),
+    user_agent text
+);
+
+CREATE INDEX idx_metadata_country ON metadata(country);
+CREATE INDEX idx_metadata_ip ON metadata(ip_address);
@@ -10,7 +10,9 @@ DROP TABLE IF EXISTS clients CASCADE;
 CREATE TABLE clients (
     id SMALLINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
     name text NOT NULL,
-    uuid UUID DEFAULT uuid_generate_v4() UNIQUE
+    uuid UUID DEFAULT uuid_generate_v4() UNIQUE,
+    -- CORS origins allowed to request tokens for this client, NULL uses the global ones
+    allowed_origins text[]
 );
 
 INSERT INTO clients (id, name, uuid)
@@ -21,7 +23,9 @@ VALUES (0, 'unknown', '00000000-0000-0000-0000-000000000000');
 DROP TABLE IF EXISTS tokens CASCADE;
 CREATE TABLE tokens (
     id ulid NOT NULL DEFAULT gen_ulid() PRIMARY KEY,
-    client_id SMALLINT NOT NULL REFERENCES clients(id) ON DELETE CASCADE
+    client_id SMALLINT NOT NULL REFERENCES clients(id) ON DELETE CASCADE,
+    -- first successful /verify, NULL if never verified
+    verified_at timestamptz
 );
 
 -- Create the table for the metadata
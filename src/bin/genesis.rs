@@ -1,5 +1,5 @@
 use anyhow::Result;
-use permesi_genesis::cli::{actions, actions::Action, start};
+use permesi_genesis::cli::{actions, actions::Action, start, telemetry};
 
 // Main function
 #[tokio::main]
@@ -8,9 +8,14 @@ async fn main() -> Result<()> {
     let (action, globals) = start().await?;
 
     // Handle the action
-    match action {
-        Action::Server { .. } => actions::server::handle(action, &globals).await?,
-    }
+    let result = match action {
+        Action::Server { .. } => actions::server::handle(action, &globals).await,
+        Action::ConfigCheck { .. } | Action::ConfigPrint { .. } => actions::config::handle(action),
+        Action::OpenApi => actions::openapi::handle(),
+    };
 
-    Ok(())
+    // send what is left in the exporters
+    telemetry::shutdown();
+
+    result
 }
@@ -0,0 +1,23 @@
+use crate::cli::actions::Action;
+use anyhow::Result;
+
+/// Handle the config actions
+/// # Errors
+/// Returns an error if the configuration is not valid or can't be printed
+pub fn handle(action: Action) -> Result<()> {
+    match action {
+        Action::ConfigCheck { config } => {
+            config.validate()?;
+
+            println!("Configuration OK");
+        }
+
+        Action::ConfigPrint { config } => {
+            print!("{}", toml::to_string_pretty(&config)?);
+        }
+
+        Action::Server { .. } | Action::OpenApi => {}
+    }
+
+    Ok(())
+}
@@ -1,6 +1,13 @@
+use crate::cli::config::Config;
+
+pub mod config;
+pub mod openapi;
 pub mod server;
 
 #[derive(Debug)]
 pub enum Action {
-    Server { port: u16, dsn: String },
+    Server { config: Config },
+    ConfigCheck { config: Config },
+    ConfigPrint { config: Config },
+    OpenApi,
 }
@@ -0,0 +1,11 @@
+use crate::genesis;
+use anyhow::Result;
+
+/// Write the `OpenAPI` spec to stdout
+/// # Errors
+/// Returns an error if the spec can't be serialized
+pub fn handle() -> Result<()> {
+    print!("{}", genesis::openapi()?);
+
+    Ok(())
+}
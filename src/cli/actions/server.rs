@@ -1,4 +1,4 @@
-use crate::cli::{actions::Action, globals::GlobalArgs};
+use crate::cli::{actions::Action, config::StoreKind, globals::GlobalArgs};
 use crate::genesis::new;
 use anyhow::{anyhow, Result};
 use secrecy::ExposeSecret;
@@ -9,19 +9,33 @@ use url::Url;
 /// Returns an error if the action fails
 pub async fn handle(action: Action, globals: &GlobalArgs) -> Result<()> {
     match action {
-        Action::Server { port, dsn } => {
-            let mut dsn = Url::parse(&dsn)?;
+        Action::Server { config } => {
+            // the in-memory store doesn't use a database
+            let dsn = match config.store {
+                StoreKind::Postgres => Some(dsn(&config.dsn, globals)?),
+                StoreKind::Memory => None,
+            };
 
-            // Set username & password from GlobalArgs
-            dsn.set_username(&globals.vault_db_username)
-                .map_err(|()| anyhow!("Error setting username"))?;
-
-            dsn.set_password(Some(globals.vault_db_password.expose_secret()))
-                .map_err(|()| anyhow!("Error setting password"))?;
-
-            new(port, dsn.to_string(), globals).await?;
+            new(config, dsn, globals).await?;
         }
+
+        Action::ConfigCheck { .. } | Action::ConfigPrint { .. } | Action::OpenApi => {}
     }
 
     Ok(())
 }
+
+// Set username & password from GlobalArgs, in dev mode the ones in the DSN are used
+fn dsn(dsn: &str, globals: &GlobalArgs) -> Result<String> {
+    let mut dsn = Url::parse(dsn)?;
+
+    if !globals.dev {
+        dsn.set_username(&globals.vault_db_username)
+            .map_err(|()| anyhow!("Error setting username"))?;
+
+        dsn.set_password(Some(globals.vault_db_password.expose_secret()))
+            .map_err(|()| anyhow!("Error setting password"))?;
+    }
+
+    Ok(dsn.to_string())
+}
@@ -1,20 +1,23 @@
-use crate::cli::actions::Action;
-use anyhow::Result;
+use crate::cli::{actions::Action, config::Config};
+use anyhow::{anyhow, Result};
 
-pub fn handler(matches: &clap::ArgMatches) -> Result<Action> {
-    // Closure to return subcommand matches
-    // let sub_m = |subcommand| -> Result<&clap::ArgMatches> {
-    //     matches
-    //         .subcommand_matches(subcommand)
-    //         .context("arguments not found")
-    // };
-    //
-    // match matches.subcommand_name() {
-    Ok(Action::Server {
-        port: matches.get_one::<u16>("port").copied().unwrap_or(8080),
-        dsn: matches
-            .get_one("dsn")
-            .map(|s: &String| s.to_string())
-            .ok_or_else(|| anyhow::anyhow!("missing required argument: --dsn"))?,
-    })
+/// Return the action to run, the server only starts with a valid configuration
+/// # Errors
+/// Will return an error if the configuration is not valid
+pub fn handler(matches: &clap::ArgMatches, config: Config) -> Result<Action> {
+    match matches.subcommand() {
+        Some(("config", sub_m)) => match sub_m.subcommand_name() {
+            Some("check") => Ok(Action::ConfigCheck { config }),
+            Some("print") => Ok(Action::ConfigPrint { config }),
+            _ => Err(anyhow!("unknown config command")),
+        },
+
+        Some(("openapi", _)) => Ok(Action::OpenApi),
+
+        _ => {
+            config.validate()?;
+
+            Ok(Action::Server { config })
+        }
+    }
 }
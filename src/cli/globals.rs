@@ -1,13 +1,16 @@
 use secrecy::SecretString;
+use std::path::PathBuf;
 
 #[derive(Debug, Clone)]
 pub struct GlobalArgs {
     pub vault_url: String,
     pub vault_token: SecretString,
+    pub vault_token_file: Option<PathBuf>,
     pub vault_db_lease_id: String,
     pub vault_db_lease_duration: u64,
     pub vault_db_username: String,
     pub vault_db_password: SecretString,
+    pub dev: bool,
 }
 
 impl GlobalArgs {
@@ -16,10 +19,21 @@ impl GlobalArgs {
         Self {
             vault_url: vurl,
             vault_token: SecretString::default(),
+            vault_token_file: None,
             vault_db_lease_id: String::new(),
             vault_db_lease_duration: 0,
             vault_db_username: String::new(),
             vault_db_password: SecretString::default(),
+            dev: false,
+        }
+    }
+
+    /// Dev mode, Vault is not used and the DSN credentials are kept
+    #[must_use]
+    pub fn dev() -> Self {
+        Self {
+            dev: true,
+            ..Self::new(String::new())
         }
     }
 
@@ -39,5 +53,15 @@ mod tests {
         let args = GlobalArgs::new(vurl);
         assert_eq!(args.vault_url, "https://localhost:8200");
         assert_eq!(args.vault_token.expose_secret(), "");
+        assert!(args.vault_token_file.is_none());
+        assert!(!args.dev);
+    }
+
+    #[test]
+    fn test_global_args_dev() {
+        let args = GlobalArgs::dev();
+        assert!(args.dev);
+        assert_eq!(args.vault_url, "");
+        assert_eq!(args.vault_db_username, "");
     }
 }
@@ -1,9 +1,10 @@
 pub mod actions;
+pub mod config;
 pub mod globals;
 pub mod telemetry;
 
 mod start;
-pub use self::start::start;
+pub use self::start::{global_args, start};
 
 mod commands;
 mod dispatch;
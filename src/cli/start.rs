@@ -1,66 +1,350 @@
-use crate::cli::{actions::Action, commands, dispatch::handler, globals::GlobalArgs, telemetry};
+use crate::cli::{
+    actions::Action,
+    commands,
+    config::{Config, VaultConfig},
+    dispatch::handler,
+    globals::GlobalArgs,
+    telemetry,
+};
 use crate::vault;
 use anyhow::{anyhow, Context, Result};
-use secrecy::SecretString;
-use tracing::debug;
+use secrecy::{ExposeSecret, SecretString};
+use tracing::{debug, warn};
 
 /// Start the CLI
 /// # Errors
-/// Will return an error if the vault role-id or vault url is not provided
+/// Will return an error if the configuration is not valid, the vault login fails
+/// or the vault token file can't be read
 pub async fn start() -> Result<(Action, GlobalArgs)> {
     let matches = commands::new().get_matches();
 
-    // vault role-id
-    let vault_role_id = matches
-        .get_one::<String>("vault-role-id")
-        .map(|s: &String| s.to_string())
-        .ok_or_else(|| anyhow!("Vault role-id is required"))?;
+    let config = Config::load(&matches)?;
 
+    let dev = config.dev;
+
+    let verbosity_level = match matches.get_one::<u8>("verbosity").map_or(0, |&v| v) {
+        // always show the dev mode warnings
+        0 if dev => tracing::Level::WARN,
+        0 => tracing::Level::ERROR,
+        1 => tracing::Level::WARN,
+        2 => tracing::Level::INFO,
+        3 => tracing::Level::DEBUG,
+        _ => tracing::Level::TRACE,
+    };
+
+    // only the server logs, `config` and `openapi` write their output to
+    // stdout, where the logs go too
+    if matches.subcommand_name().is_none() {
+        telemetry::init(verbosity_level, &config.telemetry, &config.log)?;
+    }
+
+    let action = handler(&matches, config)?;
+
+    let global_args = global_args(&action).await?;
+
+    debug!("Global args: {:?}", global_args);
+
+    Ok((action, global_args))
+}
+
+/// The global args of the action, the server logs in to Vault and gets the
+/// database credentials
+/// # Errors
+/// Will return an error if the vault login fails or the vault token file
+/// can't be read
+pub async fn global_args(action: &Action) -> Result<GlobalArgs> {
+    match action {
+        Action::Server { config } if config.dev => {
+            warn!("Running in dev mode: Vault is disabled, the username and password from the DSN are used and no credentials are renewed. Do not use this in production!");
+
+            Ok(GlobalArgs::dev())
+        }
+
+        Action::Server { config } => vault_login(&config.vault).await,
+
+        // the config and openapi commands don't need Vault
+        Action::ConfigCheck { .. } | Action::ConfigPrint { .. } | Action::OpenApi => {
+            Ok(GlobalArgs::new(String::new()))
+        }
+    }
+}
+
+/// Login to Vault and get the database credentials
+async fn vault_login(config: &VaultConfig) -> Result<GlobalArgs> {
     // vault url
-    let vault_url = matches
-        .get_one::<String>("vault-url")
-        .map(|s: &String| s.to_string())
+    let vault_url = config
+        .url
+        .clone()
         .ok_or_else(|| anyhow!("Vault URL is required"))?;
 
     let mut global_args = GlobalArgs::new(vault_url);
 
-    let vault_token: String;
+    if let Some(token_file) = &config.token_file {
+        // Vault Agent mode, the agent logs in and keeps the token renewed
+        let vault_token = vault::token_file::read(token_file)?;
 
-    // if vault wrapped token try to unwrap
-    if let Some(wrapped_token) = matches.get_one::<String>("vault-wrapped-token") {
-        let vault_session_id = vault::unwrap(&global_args.vault_url, wrapped_token).await?;
-        (vault_token, _) =
-            vault::approle_login(&global_args.vault_url, &vault_session_id, &vault_role_id).await?;
+        global_args.set_token(vault_token);
+        global_args.vault_token_file = Some(token_file.clone());
     } else {
-        let vault_session_id = matches
-            .get_one::<String>("vault-secret-id")
-            .map(|s: &String| s.to_string())
-            .ok_or_else(|| anyhow!("Vault secret-id is required"))?;
+        // vault role-id
+        let vault_role_id = config
+            .role_id
+            .clone()
+            .ok_or_else(|| anyhow!("Vault role-id is required"))?;
 
-        (vault_token, _) =
-            vault::approle_login(&global_args.vault_url, &vault_session_id, &vault_role_id).await?;
-    }
+        let vault_token: String;
+
+        // if vault wrapped token try to unwrap
+        if let Some(wrapped_token) = &config.wrapped_token {
+            let vault_session_id =
+                vault::unwrap(&global_args.vault_url, wrapped_token.expose_secret()).await?;
+            (vault_token, _) =
+                vault::approle_login(&global_args.vault_url, &vault_session_id, &vault_role_id)
+                    .await?;
+        } else {
+            let vault_session_id = config
+                .secret_id
+                .as_ref()
+                .ok_or_else(|| anyhow!("Vault secret-id is required"))?;
 
-    global_args.set_token(SecretString::from(vault_token));
+            (vault_token, _) = vault::approle_login(
+                &global_args.vault_url,
+                vault_session_id.expose_secret(),
+                &vault_role_id,
+            )
+            .await?;
+        }
+
+        global_args.set_token(SecretString::from(vault_token));
+    }
 
     // get database username and password from Vault
     vault::database::database_creds(&mut global_args)
         .await
         .context("Could not get database username and password")?;
 
-    let verbosity_level = match matches.get_one::<u8>("verbosity").map_or(0, |&v| v) {
-        0 => tracing::Level::ERROR,
-        1 => tracing::Level::WARN,
-        2 => tracing::Level::INFO,
-        3 => tracing::Level::DEBUG,
-        _ => tracing::Level::TRACE,
+    Ok(global_args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cli::{
+            actions::server,
+            config::{Config, StoreKind},
+        },
+        vault::mock::{
+            MockVault, Reply, DATABASE_CREDS, LEASE_ID, LOGIN, RENEW_SELF, REVOKE_LEASE,
+            REVOKE_SELF, SECRET_ID, TOKEN, UNWRAP,
+        },
     };
+    use reqwest::StatusCode;
+    use serde_json::Value;
+    use std::time::Duration;
+    use tokio::time::{sleep, timeout};
+    use ulid::Ulid;
+    use url::Url;
+    use uuid::Uuid;
+
+    // what start does before the action runs, without the telemetry
+    fn action(args: &[String]) -> Result<Action> {
+        let matches = commands::new().try_get_matches_from(
+            std::iter::once("genesis".to_string()).chain(args.iter().cloned()),
+        )?;
 
-    telemetry::init(verbosity_level)?;
+        handler(&matches, Config::load(&matches)?)
+    }
 
-    let action = handler(&matches)?;
+    // a server using the mock Vault, with the database of GENESIS_TEST_DSN
+    // or the in-memory store
+    fn server(vault: &MockVault, port: u16) -> Action {
+        let dsn = test_dsn(vault);
 
-    debug!("Global args: {:?}", global_args);
+        let args: Vec<String> = [
+            "--port",
+            &port.to_string(),
+            "--drain-period",
+            "0",
+            "--dsn",
+            dsn.as_deref().unwrap_or("postgres://localhost/genesis"),
+            "--vault-url",
+            &vault.login_url(),
+            "--vault-role-id",
+            "genesis",
+            "--vault-wrapped-token",
+            "hvs.wrapped",
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
 
-    Ok((action, global_args))
+        let layout = std::env::var("GENESIS_TEST_SCHEMA_LAYOUT").ok();
+        let args = [
+            args,
+            layout.map_or_else(Vec::new, |layout| {
+                vec!["--schema-layout".to_string(), layout]
+            }),
+        ]
+        .concat();
+
+        let mut action = action(&args).unwrap();
+
+        // the in-memory store is refused outside dev mode, swapped in once
+        // the configuration is validated
+        if let (Action::Server { config }, None) = (&mut action, dsn) {
+            config.store = StoreKind::Memory;
+        }
+
+        action
+    }
+
+    // GENESIS_TEST_DSN is a disposable database with the schema of
+    // GENESIS_TEST_SCHEMA_LAYOUT, the mock Vault hands out its username and
+    // password
+    fn test_dsn(vault: &MockVault) -> Option<String> {
+        let mut dsn = Url::parse(&std::env::var("GENESIS_TEST_DSN").ok()?).unwrap();
+
+        vault.script(
+            DATABASE_CREDS,
+            [Reply::creds(
+                LEASE_ID,
+                3600,
+                dsn.username(),
+                dsn.password().unwrap_or_default(),
+            )],
+        );
+
+        dsn.set_username("").unwrap();
+        dsn.set_password(None).unwrap();
+
+        Some(dsn.to_string())
+    }
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    #[tokio::test]
+    async fn test_server() {
+        let vault = MockVault::start().await;
+        let port = free_port();
+
+        let action = server(&vault, port);
+        let globals = global_args(&action).await.unwrap();
+
+        // logged in with the unwrapped secret id
+        assert_eq!(vault.calls(UNWRAP)[0].token.as_deref(), Some("hvs.wrapped"));
+        let login = &vault.calls(LOGIN)[0];
+        assert_eq!(login.body["role_id"], "genesis");
+        assert_eq!(login.body["secret_id"], SECRET_ID);
+        assert_eq!(globals.vault_token.expose_secret(), TOKEN);
+        assert_eq!(globals.vault_db_lease_id, LEASE_ID);
+
+        // renewed every second until the API is checked
+        vault.script(RENEW_SELF, [Reply::auth(TOKEN, 2)]);
+
+        let server = tokio::spawn(async move { server::handle(action, &globals).await });
+
+        let url = format!("http://127.0.0.1:{port}");
+        let http = reqwest::Client::new();
+
+        timeout(Duration::from_secs(10), async {
+            while !http
+                .get(format!("{url}/v1/readyz"))
+                .send()
+                .await
+                .is_ok_and(|response| response.status() == StatusCode::OK)
+            {
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("server not ready");
+
+        let client = permesi_genesis_client::Client::builder(&url)
+            .build()
+            .unwrap();
+
+        let token = client.issue(Uuid::new_v4()).await.unwrap();
+        client.verify(&token.token).await.unwrap();
+        assert!(client
+            .verify(&Ulid::new().to_string())
+            .await
+            .unwrap_err()
+            .is_rejected());
+
+        let health: Value = http
+            .get(format!("{url}/v1/health"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(health["vault"]["enabled"], true);
+        assert_eq!(health["vault"]["token_valid"], true);
+
+        // 3 failed renewals, 2 and 4 seconds apart, then the graceful shutdown
+        vault.script(
+            RENEW_SELF,
+            [Reply::error(StatusCode::FORBIDDEN, "permission denied")],
+        );
+
+        timeout(Duration::from_secs(20), server)
+            .await
+            .expect("server did not shut down")
+            .unwrap()
+            .unwrap();
+
+        // the credentials are released
+        assert_eq!(vault.calls(REVOKE_LEASE)[0].body["lease_id"], LEASE_ID);
+        assert_eq!(vault.calls(REVOKE_SELF).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_server_vault_errors() {
+        let vault = MockVault::start().await;
+
+        vault.script(
+            LOGIN,
+            [
+                Reply::error(StatusCode::BAD_REQUEST, "invalid role or secret ID"),
+                Reply::auth(TOKEN, 3600),
+            ],
+        );
+        let action = server(&vault, free_port());
+
+        vault.script(
+            DATABASE_CREDS,
+            [Reply::error(StatusCode::FORBIDDEN, "permission denied")],
+        );
+
+        let err = global_args(&action).await.unwrap_err();
+        assert!(err.to_string().contains("invalid role or secret ID"));
+
+        let err = global_args(&action).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Could not get database username and password"
+        );
+        assert!(format!("{err:#}").contains("permission denied"));
+    }
+
+    #[tokio::test]
+    async fn test_global_args_dev() {
+        let action = action(&[
+            "--dev".to_string(),
+            "--store".to_string(),
+            "memory".to_string(),
+        ])
+        .unwrap();
+
+        // no Vault, any call would fail
+        assert!(global_args(&action).await.unwrap().dev);
+    }
 }
@@ -0,0 +1,25 @@
+use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
+use opentelemetry_sdk::logs::LoggerProvider;
+use tracing::Subscriber;
+use tracing_subscriber::{
+    filter::{LevelFilter, Targets},
+    registry::LookupSpan,
+    Layer,
+};
+
+// the exporters log through tracing too, exporting their events would loop
+const SKIPPED_TARGETS: [&str; 6] = ["h2", "hyper", "opentelemetry", "reqwest", "tonic", "tower"];
+
+/// Send the tracing events to an OpenTelemetry logger, except the ones of the
+/// exporter crates
+pub fn layer<S>(provider: &LoggerProvider) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let targets = SKIPPED_TARGETS.iter().fold(
+        Targets::new().with_default(LevelFilter::TRACE),
+        |targets, target| targets.with_target(*target, LevelFilter::OFF),
+    );
+
+    OpenTelemetryTracingBridge::new(provider).with_filter(targets)
+}
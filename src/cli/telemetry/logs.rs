@@ -0,0 +1,339 @@
+use anyhow::{anyhow, Context, Result};
+use axum::http::{HeaderMap, HeaderName};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id},
+    subscriber::Interest,
+    Level, Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    layer::{self, Context as LayerContext},
+    registry::LookupSpan,
+};
+
+const REDACTED: &str = "********";
+
+/// Field of the request span, false when the request is sampled out of the logs
+pub const SAMPLED_FIELD: &str = "log.sampled";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Json,
+    // multi-line, for local development
+    Pretty,
+    // single line, human readable
+    Compact,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    // recorded in the request span with the value replaced
+    pub redact_headers: Vec<String>,
+    // when set only these headers are recorded, the redacted ones still are
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_headers: Option<Vec<String>>,
+    // share of the requests logged per path without the /v1 prefix, e.g.
+    // "/token" = 0.01, failures are always logged
+    pub sample: BTreeMap<String, f64>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::default(),
+            redact_headers: [
+                "authorization",
+                "cookie",
+                "proxy-authorization",
+                "set-cookie",
+                "x-vault-token",
+            ]
+            .map(String::from)
+            .to_vec(),
+            allow_headers: None,
+            sample: BTreeMap::new(),
+        }
+    }
+}
+
+impl LogConfig {
+    /// # Errors
+    /// Will return an error if a header name or a sample ratio is invalid
+    pub fn validate(&self) -> Result<()> {
+        for header in self
+            .redact_headers
+            .iter()
+            .chain(self.allow_headers.iter().flatten())
+        {
+            HeaderName::from_bytes(header.as_bytes())
+                .with_context(|| format!("Invalid log header name: {header}"))?;
+        }
+
+        for (path, ratio) in &self.sample {
+            if !path.starts_with('/') {
+                return Err(anyhow!("log sample path must start with /, got {path}"));
+            }
+
+            if !(0.0..=1.0).contains(ratio) {
+                return Err(anyhow!(
+                    "log sample ratio for {path} must be between 0 and 1, got {ratio}"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Headers to record in the request span, redacted and filtered
+    #[must_use]
+    pub fn headers(&self, headers: &HeaderMap) -> BTreeMap<String, String> {
+        let listed = |list: &[String], name: &str| {
+            list.iter().any(|header| header.eq_ignore_ascii_case(name))
+        };
+
+        headers
+            .iter()
+            .filter(|(name, _)| {
+                self.allow_headers
+                    .as_ref()
+                    .is_none_or(|allow| listed(allow, name.as_str()))
+            })
+            .map(|(name, value)| {
+                let value = if listed(&self.redact_headers, name.as_str()) {
+                    REDACTED.to_string()
+                } else {
+                    String::from_utf8_lossy(value.as_bytes()).into_owned()
+                };
+
+                (name.to_string(), value)
+            })
+            .collect()
+    }
+
+    /// Share of the requests to `path` that are logged, 1 if not sampled
+    #[must_use]
+    pub fn sample_ratio(&self, path: &str) -> f64 {
+        self.sample.get(path).copied().unwrap_or(1.0)
+    }
+
+    /// Whether to log this request to `path`
+    #[must_use]
+    pub fn sampled(&self, path: &str) -> bool {
+        // random() is in [0, 1), 0 never logs and 1 always does
+        rand::random::<f64>() < self.sample_ratio(path)
+    }
+}
+
+// in the extensions of the spans sampled out of the logs
+struct SampledOut;
+
+#[derive(Default)]
+struct SampledVisitor(Option<bool>);
+
+impl Visit for SampledVisitor {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == SAMPLED_FIELD {
+            self.0 = Some(value);
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
+}
+
+/// Mark the spans with `log.sampled = false`, read by `SampledFilter`
+pub struct SampledLayer;
+
+impl<S> tracing_subscriber::Layer<S> for SampledLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: LayerContext<'_, S>) {
+        let mut visitor = SampledVisitor::default();
+        attrs.record(&mut visitor);
+
+        if visitor.0 == Some(false) {
+            if let Some(span) = ctx.span(id) {
+                span.extensions_mut().insert(SampledOut);
+            }
+        }
+    }
+}
+
+/// Filter of the fmt layer: skip the events of the requests sampled out, the
+/// spans are still created so they are traced, errors are always logged
+pub struct SampledFilter;
+
+impl<S> layer::Filter<S> for SampledFilter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn enabled(&self, metadata: &Metadata<'_>, cx: &LayerContext<'_, S>) -> bool {
+        if !metadata.is_event() || *metadata.level() == Level::ERROR {
+            return true;
+        }
+
+        !cx.lookup_current().is_some_and(|span| {
+            span.scope()
+                .any(|span| span.extensions().get::<SampledOut>().is_some())
+        })
+    }
+
+    // depends on the current span
+    fn callsite_enabled(&self, _metadata: &'static Metadata<'static>) -> Interest {
+        Interest::sometimes()
+    }
+}
+
+/// Parse `path=ratio` pairs from flags or env vars
+/// # Errors
+/// Will return an error if a pair has no `=` or the ratio is not a number
+pub fn parse_sample(pairs: &BTreeMap<String, String>) -> Result<BTreeMap<String, f64>> {
+    pairs
+        .iter()
+        .map(|(path, ratio)| {
+            ratio
+                .parse()
+                .map(|ratio| (path.clone(), ratio))
+                .with_context(|| format!("Invalid log sample ratio for {path}: {ratio}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer secret"));
+        headers.insert("cookie", HeaderValue::from_static("session=secret"));
+        headers.insert("user-agent", HeaderValue::from_static("curl/8.5.0"));
+        headers.insert("x-request-id", HeaderValue::from_static("01HQ"));
+        headers
+    }
+
+    #[test]
+    fn test_headers_redacted() {
+        let recorded = LogConfig::default().headers(&headers());
+        assert_eq!(recorded["authorization"], REDACTED);
+        assert_eq!(recorded["cookie"], REDACTED);
+        assert_eq!(recorded["user-agent"], "curl/8.5.0");
+        assert_eq!(recorded.len(), 4);
+        assert!(!format!("{recorded:?}").contains("secret"));
+    }
+
+    #[test]
+    fn test_headers_allowed() {
+        let config = LogConfig {
+            allow_headers: Some(vec!["User-Agent".to_string(), "Cookie".to_string()]),
+            ..LogConfig::default()
+        };
+
+        let recorded = config.headers(&headers());
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(recorded["user-agent"], "curl/8.5.0");
+        assert_eq!(recorded["cookie"], REDACTED);
+    }
+
+    #[test]
+    fn test_sampled() {
+        let config = LogConfig {
+            sample: BTreeMap::from([("/health".to_string(), 0.0), ("/token".to_string(), 0.1)]),
+            ..LogConfig::default()
+        };
+
+        assert!(!config.sampled("/health"));
+        assert!(config.sampled("/verify"));
+        assert!((config.sample_ratio("/token") - 0.1).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_sampled_filter() {
+        use std::sync::{Arc, Mutex};
+        use tracing::{debug_span, error, info, Event};
+        use tracing_subscriber::{layer::SubscriberExt, Layer, Registry};
+
+        // the messages of the events that got through
+        #[derive(Clone, Default)]
+        struct Events(Arc<Mutex<Vec<String>>>);
+
+        impl<S: Subscriber> Layer<S> for Events {
+            fn on_event(&self, event: &Event<'_>, _ctx: LayerContext<'_, S>) {
+                struct Message(String);
+
+                impl Visit for Message {
+                    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                        if field.name() == "message" {
+                            self.0 = format!("{value:?}");
+                        }
+                    }
+                }
+
+                let mut message = Message(String::new());
+                event.record(&mut message);
+                self.0.lock().unwrap().push(message.0);
+            }
+        }
+
+        let events = Events::default();
+        let subscriber = Registry::default()
+            .with(events.clone().with_filter(SampledFilter))
+            .with(SampledLayer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            debug_span!("http-request", log.sampled = true).in_scope(|| info!("sampled"));
+
+            debug_span!("http-request", log.sampled = false).in_scope(|| {
+                info!("skipped");
+                debug_span!("query").in_scope(|| info!("nested"));
+                error!("failed");
+            });
+
+            info!("outside");
+        });
+
+        assert_eq!(
+            *events.0.lock().unwrap(),
+            vec!["sampled", "failed", "outside"]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(LogConfig::default().validate().is_ok());
+
+        let config = LogConfig {
+            sample: BTreeMap::from([("/token".to_string(), 2.0)]),
+            ..LogConfig::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = LogConfig {
+            sample: BTreeMap::from([("token".to_string(), 0.5)]),
+            ..LogConfig::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = LogConfig {
+            allow_headers: Some(vec!["not a header".to_string()]),
+            ..LogConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_parse_sample() {
+        let pairs = BTreeMap::from([("/token".to_string(), "0.01".to_string())]);
+        assert_eq!(parse_sample(&pairs).unwrap()["/token"], 0.01);
+
+        let pairs = BTreeMap::from([("/token".to_string(), "often".to_string())]);
+        assert!(parse_sample(&pairs).is_err());
+    }
+}
@@ -0,0 +1,676 @@
+use anyhow::{anyhow, Context, Result};
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use opentelemetry::{global, trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::{WithExportConfig, WithHttpConfig, WithTonicConfig};
+use opentelemetry_sdk::{
+    logs::LoggerProvider,
+    metrics::{PeriodicReader, SdkMeterProvider},
+    propagation::TraceContextPropagator,
+    runtime,
+    trace::{Sampler, Tracer, TracerProvider},
+    Resource,
+};
+use serde::{Deserialize, Serialize, Serializer};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::OnceLock,
+    time::Duration,
+};
+use tonic::metadata::MetadataMap;
+use tracing::warn;
+use tracing_subscriber::{fmt, layer::SubscriberExt, EnvFilter, Layer, Registry};
+
+pub mod appender;
+pub mod logs;
+pub mod propagation;
+pub mod stdout;
+
+use logs::{LogConfig, LogFormat};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Exporter {
+    #[default]
+    Otlp,
+    // print the spans as JSON lines, for debugging
+    Stdout,
+    // tracing disabled
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Signal {
+    Traces,
+    Metrics,
+    Logs,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Protocol {
+    #[default]
+    #[serde(rename = "grpc")]
+    Grpc,
+    #[serde(rename = "http/protobuf")]
+    HttpProtobuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    pub exporter: Exporter,
+    // exported over OTLP, the stdout exporter only prints the traces
+    pub signals: BTreeSet<Signal>,
+    // OTLP collector, defaults to OTEL_EXPORTER_OTLP_ENDPOINT or the SDK default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    pub protocol: Protocol,
+    // sent with every export, e.g. authorization
+    #[serde(serialize_with = "redact_values")]
+    pub headers: BTreeMap<String, String>,
+    // seconds
+    pub timeout: u64,
+    // share of the root traces sampled, child spans follow their parent
+    pub sample_ratio: f64,
+    // seconds between metric exports
+    pub metrics_interval: u64,
+    // added to service.name and service.version, e.g. deployment.environment
+    pub resource_attributes: BTreeMap<String, String>,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            exporter: Exporter::default(),
+            signals: BTreeSet::from([Signal::Traces, Signal::Metrics, Signal::Logs]),
+            endpoint: None,
+            protocol: Protocol::default(),
+            headers: BTreeMap::new(),
+            timeout: 3,
+            sample_ratio: 1.0,
+            metrics_interval: 60,
+            resource_attributes: BTreeMap::new(),
+        }
+    }
+}
+
+impl TelemetryConfig {
+    /// # Errors
+    /// Will return an error if the sample ratio, the metrics interval or a
+    /// header is invalid
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.sample_ratio) {
+            return Err(anyhow!(
+                "telemetry sample_ratio must be between 0 and 1, got {}",
+                self.sample_ratio
+            ));
+        }
+
+        if self.metrics_interval == 0 {
+            return Err(anyhow!("telemetry metrics_interval must be greater than 0"));
+        }
+
+        self.header_map()?;
+
+        Ok(())
+    }
+
+    fn header_map(&self) -> Result<HeaderMap> {
+        self.headers
+            .iter()
+            .map(|(name, value)| {
+                Ok((
+                    HeaderName::from_bytes(name.as_bytes())
+                        .with_context(|| format!("Invalid telemetry header name: {name}"))?,
+                    HeaderValue::from_str(value)
+                        .with_context(|| format!("Invalid telemetry header value for {name}"))?,
+                ))
+            })
+            .collect()
+    }
+
+    fn resource(&self) -> Resource {
+        let mut attributes = vec![
+            KeyValue::new("service.name", env!("CARGO_PKG_NAME")),
+            KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+        ];
+
+        attributes.extend(
+            self.resource_attributes
+                .iter()
+                .map(|(key, value)| KeyValue::new(key.clone(), value.clone())),
+        );
+
+        // OTEL_RESOURCE_ATTRIBUTES is still honored, the config wins
+        Resource::default().merge(&Resource::new(attributes))
+    }
+
+    // metrics and logs are only exported over OTLP
+    fn exports(&self, signal: Signal) -> bool {
+        self.signals.contains(&signal)
+            && match self.exporter {
+                Exporter::Otlp => true,
+                Exporter::Stdout => signal == Signal::Traces,
+                Exporter::None => false,
+            }
+    }
+
+    fn sampler(&self) -> Sampler {
+        Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(self.sample_ratio)))
+    }
+}
+
+/// Parse `key=value` pairs from flags or env vars
+/// # Errors
+/// Will return an error if a pair has no `=`
+pub fn parse_pairs(pairs: &[String]) -> Result<BTreeMap<String, String>> {
+    pairs
+        .iter()
+        .map(|pair| {
+            pair.split_once('=')
+                .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+                .ok_or_else(|| anyhow!("Expected key=value, got {pair}"))
+        })
+        .collect()
+}
+
+// OTLP/gRPC settings shared by the signals
+fn grpc<B: WithExportConfig + WithTonicConfig>(builder: B, config: &TelemetryConfig) -> Result<B> {
+    let mut builder = builder
+        .with_timeout(Duration::from_secs(config.timeout))
+        .with_metadata(MetadataMap::from_headers(config.header_map()?));
+
+    if let Some(endpoint) = &config.endpoint {
+        builder = builder.with_endpoint(endpoint);
+    }
+
+    Ok(builder)
+}
+
+// OTLP/HTTP settings shared by the signals
+fn http<B: WithExportConfig + WithHttpConfig>(
+    builder: B,
+    config: &TelemetryConfig,
+    signal: &str,
+) -> B {
+    let mut builder = builder
+        .with_protocol(opentelemetry_otlp::Protocol::HttpBinary)
+        .with_timeout(Duration::from_secs(config.timeout))
+        .with_headers(config.headers.clone().into_iter().collect());
+
+    // the SDK only appends the path to the env var endpoints
+    if let Some(endpoint) = &config.endpoint {
+        builder = builder.with_endpoint(signal_endpoint(endpoint, signal));
+    }
+
+    builder
+}
+
+fn init_tracer(config: &TelemetryConfig) -> Result<Option<Tracer>> {
+    if !config.exports(Signal::Traces) {
+        return Ok(None);
+    }
+
+    let builder = TracerProvider::builder()
+        .with_sampler(config.sampler())
+        .with_resource(config.resource());
+
+    let tracer_provider = if config.exporter == Exporter::Stdout {
+        builder.with_simple_exporter(stdout::Exporter)
+    } else {
+        let exporter = match config.protocol {
+            Protocol::Grpc => grpc(
+                opentelemetry_otlp::SpanExporter::builder().with_tonic(),
+                config,
+            )?
+            .build(),
+            Protocol::HttpProtobuf => http(
+                opentelemetry_otlp::SpanExporter::builder().with_http(),
+                config,
+                "traces",
+            )
+            .build(),
+        }
+        .context("Failed to create the OTLP exporter")?;
+
+        builder.with_batch_exporter(exporter, runtime::Tokio)
+    }
+    .build();
+
+    global::set_tracer_provider(tracer_provider.clone());
+
+    Ok(Some(tracer_provider.tracer(env!("CARGO_PKG_NAME"))))
+}
+
+fn meter_provider(config: &TelemetryConfig) -> Result<Option<SdkMeterProvider>> {
+    if !config.exports(Signal::Metrics) {
+        return Ok(None);
+    }
+
+    let exporter = match config.protocol {
+        Protocol::Grpc => grpc(
+            opentelemetry_otlp::MetricExporter::builder().with_tonic(),
+            config,
+        )?
+        .build(),
+        Protocol::HttpProtobuf => http(
+            opentelemetry_otlp::MetricExporter::builder().with_http(),
+            config,
+            "metrics",
+        )
+        .build(),
+    }
+    .context("Failed to create the OTLP metric exporter")?;
+
+    let reader = PeriodicReader::builder(exporter, runtime::Tokio)
+        .with_interval(Duration::from_secs(config.metrics_interval))
+        .build();
+
+    Ok(Some(
+        SdkMeterProvider::builder()
+            .with_reader(reader)
+            .with_resource(config.resource())
+            .build(),
+    ))
+}
+
+fn logger_provider(config: &TelemetryConfig) -> Result<Option<LoggerProvider>> {
+    if !config.exports(Signal::Logs) {
+        return Ok(None);
+    }
+
+    let exporter = match config.protocol {
+        Protocol::Grpc => grpc(
+            opentelemetry_otlp::LogExporter::builder().with_tonic(),
+            config,
+        )?
+        .build(),
+        Protocol::HttpProtobuf => http(
+            opentelemetry_otlp::LogExporter::builder().with_http(),
+            config,
+            "logs",
+        )
+        .build(),
+    }
+    .context("Failed to create the OTLP log exporter")?;
+
+    Ok(Some(
+        LoggerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(config.resource())
+            .build(),
+    ))
+}
+
+// OTLP/HTTP path of the signal, e.g. /v1/traces
+fn signal_endpoint(endpoint: &str, signal: &str) -> String {
+    let mut endpoint = endpoint.trim_end_matches('/');
+
+    for path in ["/v1/traces", "/v1/metrics", "/v1/logs"] {
+        endpoint = endpoint.strip_suffix(path).unwrap_or(endpoint);
+    }
+
+    format!("{endpoint}/v1/{signal}")
+}
+
+// kept to flush the pending metrics and logs on exit
+struct Providers {
+    meter: Option<SdkMeterProvider>,
+    logger: Option<LoggerProvider>,
+}
+
+static PROVIDERS: OnceLock<Providers> = OnceLock::new();
+
+/// Start the telemetry layer
+/// # Errors
+/// Will return an error if the telemetry layer fails to start
+pub fn init(
+    verbosity_level: tracing::Level,
+    config: &TelemetryConfig,
+    log: &LogConfig,
+) -> Result<()> {
+    // W3C traceparent/tracestate, also when not exporting so the context is
+    // passed on to Vault
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let otel_tracer_layer =
+        init_tracer(config)?.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+
+    let meter_provider = meter_provider(config)?;
+
+    if let Some(meter_provider) = &meter_provider {
+        global::set_meter_provider(meter_provider.clone());
+    }
+
+    let logger_provider = logger_provider(config)?;
+
+    let otel_logs_layer = logger_provider.as_ref().map(appender::layer);
+
+    let fmt_layer = fmt::layer()
+        .with_file(true)
+        .with_line_number(true)
+        .with_thread_ids(false)
+        .with_thread_names(false)
+        .with_target(false);
+
+    let fmt_layer = match log.format {
+        LogFormat::Json => fmt_layer.json().boxed(),
+        LogFormat::Pretty => fmt_layer.pretty().boxed(),
+        LogFormat::Compact => fmt_layer.compact().boxed(),
+    };
+
+    // RUST_LOG=
+    let filter = EnvFilter::builder()
+        .with_default_directive(verbosity_level.into())
+        .from_env_lossy()
+        .add_directive("hyper=error".parse()?)
+        .add_directive("tokio=error".parse()?)
+        .add_directive("reqwest=error".parse()?);
+
+    // the requests sampled out of the logs are still traced
+    let subscriber = Registry::default()
+        .with(fmt_layer.with_filter(logs::SampledFilter))
+        .with(logs::SampledLayer)
+        .with(otel_tracer_layer)
+        .with(otel_logs_layer)
+        .with(filter);
+
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    let _ = PROVIDERS.set(Providers {
+        meter: meter_provider,
+        logger: logger_provider,
+    });
+
+    Ok(())
+}
+
+/// Flush the pending spans, metrics and logs and stop the exporters
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+
+    let Some(providers) = PROVIDERS.get() else {
+        return;
+    };
+
+    if let Some(meter_provider) = &providers.meter {
+        if let Err(err) = meter_provider.shutdown() {
+            warn!("Failed to export the metrics: {}", err);
+        }
+    }
+
+    // last, the errors above are still exported
+    if let Some(logger_provider) = &providers.logger {
+        let _ = logger_provider.shutdown();
+    }
+}
+
+fn redact_values<S: Serializer>(
+    headers: &BTreeMap<String, String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(headers.keys().map(|key| (key, "********")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Bytes,
+        http::{StatusCode, Uri},
+        routing::post,
+        Router,
+    };
+    use opentelemetry::metrics::MeterProvider as _;
+    use opentelemetry_proto::tonic::{
+        collector::{logs::v1::ExportLogsServiceRequest, metrics::v1::ExportMetricsServiceRequest},
+        common::v1::{any_value, KeyValue as ProtoKeyValue},
+    };
+    use prost::Message;
+    use std::sync::{Arc, Mutex};
+    use tracing::info;
+
+    type Received = Arc<Mutex<Vec<(String, Bytes)>>>;
+
+    // in-process OTLP/HTTP collector, keeps the path and body of the exports
+    async fn collector() -> (String, Received) {
+        let received = Received::default();
+
+        let app = Router::new().route(
+            "/v1/:signal",
+            post({
+                let received = received.clone();
+                move |uri: Uri, body: Bytes| async move {
+                    received
+                        .lock()
+                        .unwrap()
+                        .push((uri.path().to_string(), body));
+                    StatusCode::OK
+                }
+            }),
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        (endpoint, received)
+    }
+
+    fn collector_config(endpoint: String) -> TelemetryConfig {
+        TelemetryConfig {
+            endpoint: Some(endpoint),
+            protocol: Protocol::HttpProtobuf,
+            resource_attributes: BTreeMap::from([(
+                "deployment.environment".to_string(),
+                "test".to_string(),
+            )]),
+            ..TelemetryConfig::default()
+        }
+    }
+
+    fn attribute(attributes: &[ProtoKeyValue], key: &str) -> Option<String> {
+        attributes
+            .iter()
+            .find(|attribute| attribute.key == key)
+            .and_then(|attribute| attribute.value.as_ref()?.value.clone())
+            .map(|value| match value {
+                any_value::Value::StringValue(value) => value,
+                value => format!("{value:?}"),
+            })
+    }
+
+    fn exported(received: &Received, path: &str) -> Bytes {
+        received
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(received, _)| received == path)
+            .map(|(_, body)| body.clone())
+            .unwrap_or_else(|| panic!("nothing exported to {path}"))
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(TelemetryConfig::default().validate().is_ok());
+
+        let config = TelemetryConfig {
+            sample_ratio: 1.5,
+            ..TelemetryConfig::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = TelemetryConfig {
+            metrics_interval: 0,
+            ..TelemetryConfig::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = TelemetryConfig {
+            headers: BTreeMap::from([("not a header".to_string(), "x".to_string())]),
+            ..TelemetryConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_parse_pairs() {
+        let pairs = parse_pairs(&[
+            "deployment.environment=production".to_string(),
+            "authorization = Bearer x=y".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(pairs["deployment.environment"], "production");
+        assert_eq!(pairs["authorization"], "Bearer x=y");
+
+        assert!(parse_pairs(&["nope".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_signal_endpoint() {
+        assert_eq!(
+            signal_endpoint("http://collector:4318", "traces"),
+            "http://collector:4318/v1/traces"
+        );
+        assert_eq!(
+            signal_endpoint("http://collector:4318/v1/traces/", "traces"),
+            "http://collector:4318/v1/traces"
+        );
+        assert_eq!(
+            signal_endpoint("http://collector:4318/v1/traces", "logs"),
+            "http://collector:4318/v1/logs"
+        );
+    }
+
+    #[test]
+    fn test_resource() {
+        let config = TelemetryConfig {
+            resource_attributes: BTreeMap::from([(
+                "deployment.environment".to_string(),
+                "staging".to_string(),
+            )]),
+            ..TelemetryConfig::default()
+        };
+
+        let resource = config.resource();
+        assert_eq!(
+            resource
+                .get(opentelemetry::Key::from_static_str(
+                    "deployment.environment"
+                ))
+                .map(|value| value.to_string()),
+            Some("staging".to_string())
+        );
+        assert_eq!(
+            resource
+                .get(opentelemetry::Key::from_static_str("service.name"))
+                .map(|value| value.to_string()),
+            Some(env!("CARGO_PKG_NAME").to_string())
+        );
+    }
+
+    #[test]
+    fn test_exports() {
+        let config = TelemetryConfig::default();
+        assert!(config.exports(Signal::Metrics));
+
+        let config = TelemetryConfig {
+            exporter: Exporter::Stdout,
+            ..TelemetryConfig::default()
+        };
+        assert!(config.exports(Signal::Traces));
+        assert!(!config.exports(Signal::Logs));
+
+        let config = TelemetryConfig {
+            signals: BTreeSet::from([Signal::Traces]),
+            ..TelemetryConfig::default()
+        };
+        assert!(!config.exports(Signal::Metrics));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_export_metrics() {
+        let (endpoint, received) = collector().await;
+
+        let provider = meter_provider(&collector_config(endpoint))
+            .unwrap()
+            .unwrap();
+
+        provider
+            .meter("test")
+            .u64_counter("genesis.tokens.issued")
+            .build()
+            .add(1, &[KeyValue::new("result", "ok")]);
+
+        // shutdown blocks until the last export is done
+        tokio::task::spawn_blocking(move || provider.shutdown())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let request =
+            ExportMetricsServiceRequest::decode(exported(&received, "/v1/metrics")).unwrap();
+        let resource_metrics = &request.resource_metrics[0];
+        let resource = resource_metrics.resource.as_ref().unwrap();
+
+        assert_eq!(
+            attribute(&resource.attributes, "service.name").as_deref(),
+            Some(env!("CARGO_PKG_NAME"))
+        );
+        assert_eq!(
+            attribute(&resource.attributes, "deployment.environment").as_deref(),
+            Some("test")
+        );
+        assert!(resource_metrics
+            .scope_metrics
+            .iter()
+            .flat_map(|scope| &scope.metrics)
+            .any(|metric| metric.name == "genesis.tokens.issued"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_export_logs() {
+        let (endpoint, received) = collector().await;
+
+        let provider = logger_provider(&collector_config(endpoint))
+            .unwrap()
+            .unwrap();
+
+        let subscriber = Registry::default().with(appender::layer(&provider));
+
+        tracing::subscriber::with_default(subscriber, || {
+            info!(target: "hyper::proto", "Connection closed");
+            info!(client = "permesi", "Token issued");
+        });
+
+        tokio::task::spawn_blocking(move || provider.shutdown())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let request = ExportLogsServiceRequest::decode(exported(&received, "/v1/logs")).unwrap();
+        let resource_logs = &request.resource_logs[0];
+        let resource = resource_logs.resource.as_ref().unwrap();
+
+        assert_eq!(
+            attribute(&resource.attributes, "deployment.environment").as_deref(),
+            Some("test")
+        );
+
+        // the events of the exporter crates are not exported
+        let records = &resource_logs.scope_logs[0].log_records;
+        assert_eq!(records.len(), 1);
+
+        let record = &records[0];
+        assert_eq!(record.severity_text, "INFO");
+        assert_eq!(
+            record.body.as_ref().and_then(|body| body.value.clone()),
+            Some(any_value::Value::StringValue("Token issued".to_string()))
+        );
+        assert_eq!(
+            attribute(&record.attributes, "client").as_deref(),
+            Some("permesi")
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use axum::http::HeaderMap;
+use opentelemetry::{global, Context};
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Remote context from the `traceparent` and `tracestate` headers, empty if
+/// there are none
+#[must_use]
+pub fn extract(headers: &HeaderMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+/// `traceparent` and `tracestate` headers for the current span, to continue
+/// the trace in outgoing requests
+#[must_use]
+pub fn inject() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(
+            &Span::current().context(),
+            &mut HeaderInjector(&mut headers),
+        );
+    });
+
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+    use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::TracerProvider};
+    use tracing::info_span;
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+    #[test]
+    fn test_extract_inject() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let provider = TracerProvider::builder().build();
+        let subscriber = Registry::default()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                "traceparent",
+                HeaderValue::from_str(&format!("00-{TRACE_ID}-00f067aa0ba902b7-01")).unwrap(),
+            );
+
+            let span = info_span!("http-request");
+            span.set_parent(extract(&headers));
+
+            // the request span continues the remote trace
+            assert_eq!(
+                span.context().span().span_context().trace_id().to_string(),
+                TRACE_ID
+            );
+
+            // and outgoing requests continue it too
+            let outgoing = span.in_scope(inject);
+            let traceparent = outgoing.get("traceparent").unwrap().to_str().unwrap();
+            assert!(traceparent.starts_with(&format!("00-{TRACE_ID}-")));
+            assert!(!traceparent.contains("00f067aa0ba902b7"));
+        });
+    }
+
+    #[test]
+    fn test_extract_without_headers() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let context = extract(&HeaderMap::new());
+        assert!(!context.span().span_context().is_valid());
+    }
+}
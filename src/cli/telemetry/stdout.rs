@@ -0,0 +1,70 @@
+use chrono::{DateTime, Utc};
+use opentelemetry::trace::{SpanId, Status};
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    future::{self, Future},
+    io::{self, Write},
+    pin::Pin,
+};
+
+/// Print the finished spans to stdout as JSON lines
+#[derive(Debug, Default)]
+pub struct Exporter;
+
+#[derive(Serialize)]
+struct Span {
+    name: String,
+    trace_id: String,
+    span_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_span_id: Option<String>,
+    kind: String,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    attributes: BTreeMap<String, String>,
+    status: String,
+}
+
+impl From<&SpanData> for Span {
+    fn from(span: &SpanData) -> Self {
+        Self {
+            name: span.name.to_string(),
+            trace_id: span.span_context.trace_id().to_string(),
+            span_id: span.span_context.span_id().to_string(),
+            parent_span_id: (span.parent_span_id != SpanId::INVALID)
+                .then(|| span.parent_span_id.to_string()),
+            kind: format!("{:?}", span.span_kind),
+            start: span.start_time.into(),
+            end: span.end_time.into(),
+            attributes: span
+                .attributes
+                .iter()
+                .map(|kv| (kv.key.to_string(), kv.value.to_string()))
+                .collect(),
+            status: match &span.status {
+                Status::Unset => "unset".to_string(),
+                Status::Ok => "ok".to_string(),
+                Status::Error { description } => format!("error: {description}"),
+            },
+        }
+    }
+}
+
+impl SpanExporter for Exporter {
+    fn export(
+        &mut self,
+        batch: Vec<SpanData>,
+    ) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+        let mut stdout = io::stdout().lock();
+
+        for span in &batch {
+            if let Ok(line) = serde_json::to_string(&Span::from(span)) {
+                let _ = writeln!(stdout, "{line}");
+            }
+        }
+
+        Box::pin(future::ready(Ok(())))
+    }
+}
@@ -0,0 +1,218 @@
+use crate::genesis::store::{Client, TokenStore};
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Request, State},
+    http::{header::CONTENT_TYPE, request::Parts, HeaderValue, Method, Uri},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{debug, error};
+use url::form_urlencoded;
+use uuid::Uuid;
+
+const ANY: &str = "*";
+
+/// Client of the `client_id` in the query, looked up once per /token request
+/// by [`lookup_client`] and shared by the CORS check and the handler
+#[derive(Debug, Clone)]
+pub struct RequestClient(pub Option<Client>);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    // global allowed origins, `*` allows any origin
+    pub allowed_origins: Vec<String>,
+    // allowed origins for /verify, defaults to the global ones
+    pub verify_allowed_origins: Option<Vec<String>>,
+    pub allow_credentials: bool,
+    // seconds the preflight response can be cached
+    pub max_age: Option<u64>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec![ANY.to_string()],
+            verify_allowed_origins: None,
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl CorsConfig {
+    /// Browsers reject credentials with a wildcard origin
+    /// # Errors
+    /// Will return an error if credentials are allowed for any origin
+    pub fn validate(&self) -> Result<()> {
+        let any = |origins: &[String]| origins.iter().any(|o| o == ANY);
+
+        if self.allow_credentials
+            && (any(&self.allowed_origins)
+                || self.verify_allowed_origins.as_deref().is_some_and(any))
+        {
+            return Err(anyhow!(
+                "CORS credentials can't be allowed for any origin (*), list the allowed origins"
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn layer(&self, methods: [Method; 2], allow_origin: AllowOrigin) -> CorsLayer {
+        let layer = CorsLayer::new()
+            .allow_methods(methods)
+            .allow_origin(allow_origin)
+            .allow_credentials(self.allow_credentials);
+
+        match self.max_age {
+            Some(max_age) => layer.max_age(Duration::from_secs(max_age)),
+            None => layer,
+        }
+    }
+
+    /// CORS for /headers
+    pub fn global(&self) -> CorsLayer {
+        self.layer(
+            [Method::GET, Method::POST],
+            allow_origin(&self.allowed_origins),
+        )
+    }
+
+    /// CORS for /verify
+    pub fn verify(&self) -> CorsLayer {
+        let origins = self
+            .verify_allowed_origins
+            .as_ref()
+            .unwrap_or(&self.allowed_origins);
+
+        self.layer([Method::POST, Method::OPTIONS], allow_origin(origins))
+            .allow_headers([CONTENT_TYPE])
+    }
+
+    /// CORS for /token, only origins registered to the `client_id` in the query
+    /// are allowed, clients without registered origins use the global ones
+    pub fn token(&self) -> CorsLayer {
+        let global = self.allowed_origins.clone();
+
+        let allow_origin = AllowOrigin::predicate(move |origin: &HeaderValue, parts: &Parts| {
+            let Ok(origin) = origin.to_str() else {
+                return false;
+            };
+
+            let registered = parts
+                .extensions
+                .get::<RequestClient>()
+                .and_then(|client| client.0.as_ref())
+                .map(|client| client.allowed_origins.as_slice())
+                .unwrap_or_default();
+
+            let allowed = if registered.is_empty() {
+                is_allowed(&global, origin)
+            } else {
+                registered.iter().any(|o| o == origin)
+            };
+
+            debug!("CORS origin {} allowed: {}", origin, allowed);
+
+            allowed
+        });
+
+        self.layer([Method::GET, Method::OPTIONS], allow_origin)
+    }
+}
+
+fn allow_origin(origins: &[String]) -> AllowOrigin {
+    if origins.iter().any(|o| o == ANY) {
+        return AllowOrigin::any();
+    }
+
+    AllowOrigin::list(
+        origins
+            .iter()
+            .filter_map(|origin| HeaderValue::from_str(origin).ok()),
+    )
+}
+
+fn is_allowed(origins: &[String], origin: &str) -> bool {
+    origins.iter().any(|o| o == ANY || o == origin)
+}
+
+fn client_id(uri: &Uri) -> Option<Uuid> {
+    form_urlencoded::parse(uri.query()?.as_bytes())
+        .find(|(key, _)| key == "client_id")
+        .and_then(|(_, value)| value.parse::<Uuid>().ok())
+}
+
+/// Middleware looking up the client of the `client_id` in the query, a failed
+/// lookup is logged and left to the handler
+pub async fn lookup_client(
+    State(store): State<Arc<dyn TokenStore>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if let Some(client_id) = client_id(request.uri()) {
+        match store.lookup_client(client_id).await {
+            Ok(client) => {
+                request.extensions_mut().insert(RequestClient(client));
+            }
+            Err(err) => error!("Failed to retrieve client: {}", err),
+        }
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(CorsConfig::default().validate().is_ok());
+
+        let config = CorsConfig {
+            allow_credentials: true,
+            ..CorsConfig::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = CorsConfig {
+            allowed_origins: vec!["https://permesi.dev".to_string()],
+            allow_credentials: true,
+            ..CorsConfig::default()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = CorsConfig {
+            verify_allowed_origins: Some(vec![ANY.to_string()]),
+            ..config
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        assert!(is_allowed(&[ANY.to_string()], "https://permesi.dev"));
+        assert!(is_allowed(
+            &["https://permesi.dev".to_string()],
+            "https://permesi.dev"
+        ));
+        assert!(!is_allowed(
+            &["https://permesi.dev".to_string()],
+            "https://evil.dev"
+        ));
+    }
+
+    #[test]
+    fn test_client_id() {
+        let uri = Uri::from_static("/token?client_id=00000000-0000-0000-0000-000000000000");
+        assert_eq!(client_id(&uri), Some(Uuid::nil()));
+
+        let uri = Uri::from_static("/token?client_id=nope");
+        assert_eq!(client_id(&uri), None);
+    }
+}
@@ -0,0 +1,252 @@
+use axum::{
+    body::Body,
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        Request,
+    },
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use permesi_genesis_client::ProblemKind;
+use tracing::error;
+
+pub use permesi_genesis_client::Problem;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+// seconds, for the clients of a 503
+const RETRY_AFTER: u32 = 5;
+
+/// Errors returned by the handlers, rendered as RFC 7807 problem details, the
+/// variants are described on their `ProblemKind`
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    MalformedToken,
+    TokenExpired,
+    InvalidToken,
+    NotImplemented(String),
+    Unavailable,
+    Internal(anyhow::Error),
+}
+
+impl ApiError {
+    #[must_use]
+    pub const fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) | Self::MalformedToken => StatusCode::BAD_REQUEST,
+            Self::TokenExpired | Self::InvalidToken => StatusCode::FORBIDDEN,
+            Self::NotImplemented(_) => StatusCode::NOT_IMPLEMENTED,
+            Self::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    #[must_use]
+    pub const fn kind(&self) -> ProblemKind {
+        match self {
+            Self::BadRequest(_) => ProblemKind::BadRequest,
+            Self::MalformedToken => ProblemKind::MalformedToken,
+            Self::TokenExpired => ProblemKind::TokenExpired,
+            Self::InvalidToken => ProblemKind::InvalidToken,
+            Self::NotImplemented(_) => ProblemKind::NotImplemented,
+            Self::Unavailable => ProblemKind::Unavailable,
+            Self::Internal(_) => ProblemKind::Internal,
+        }
+    }
+
+    const fn title(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_) => "Bad request",
+            Self::MalformedToken => "Malformed token",
+            Self::TokenExpired => "Token expired",
+            Self::InvalidToken => "Invalid token",
+            Self::NotImplemented(_) => "Not implemented",
+            Self::Unavailable => "Service unavailable",
+            Self::Internal(_) => "Internal server error",
+        }
+    }
+
+    fn detail(&self) -> String {
+        match self {
+            Self::BadRequest(detail) | Self::NotImplemented(detail) => detail.clone(),
+            Self::MalformedToken => "The token is not a valid ULID".to_string(),
+            Self::TokenExpired => "The token is too old to be verified".to_string(),
+            Self::InvalidToken => "The token was not issued by genesis".to_string(),
+            Self::Unavailable => {
+                "The database credentials are being renewed, try again later".to_string()
+            }
+            Self::Internal(_) => "The request failed, try again later".to_string(),
+        }
+    }
+
+    #[must_use]
+    pub fn problem(&self) -> Problem {
+        Problem {
+            kind: self.kind().problem_type(),
+            title: self.title().to_string(),
+            status: self.status().as_u16(),
+            detail: self.detail(),
+            request_id: None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::Internal(err)
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::BadRequest(rejection.body_text())
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::BadRequest(rejection.body_text())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if let Self::Internal(err) = &self {
+            error!("{:#}", err);
+        }
+
+        let problem = self.problem();
+
+        let mut response = problem_response(&problem);
+
+        if matches!(self, Self::Unavailable) {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(RETRY_AFTER));
+        }
+
+        // for `request_id` to fill in
+        response.extensions_mut().insert(problem);
+
+        response
+    }
+}
+
+fn problem_response(problem: &Problem) -> Response {
+    let body = serde_json::to_vec(problem).unwrap_or_default();
+
+    let mut response = (
+        StatusCode::from_u16(problem.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+        body,
+    )
+        .into_response();
+
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+
+    response
+}
+
+/// Middleware adding the `x-request-id` to the problem responses
+pub async fn request_id(request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .map(ToString::to_string);
+
+    let mut response = next.run(request).await;
+
+    match (request_id, response.extensions_mut().remove::<Problem>()) {
+        (Some(request_id), Some(mut problem)) => {
+            problem.request_id = Some(request_id);
+
+            let (mut parts, _) = response.into_parts();
+            let (_, body) = problem_response(&problem).into_parts();
+
+            // the body is longer now
+            parts.headers.remove(header::CONTENT_LENGTH);
+
+            Response::from_parts(parts, Body::new(body))
+        }
+        _ => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use axum::{middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    async fn problem(response: Response) -> Problem {
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            PROBLEM_JSON
+        );
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_internal_details_hidden() {
+        let err = ApiError::from(anyhow!(
+            "error returned from database: password authentication failed"
+        ));
+
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let problem = problem(response).await;
+        assert_eq!(problem.kind, "urn:permesi:genesis:internal");
+        assert_eq!(problem.status, 500);
+        assert!(!problem.detail.contains("password"));
+        assert_eq!(problem.request_id, None);
+    }
+
+    #[tokio::test]
+    async fn test_unavailable() {
+        let response = ApiError::Unavailable.into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "5");
+
+        let problem = problem(response).await;
+        assert_eq!(problem.kind, "urn:permesi:genesis:unavailable");
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let app = Router::new()
+            .route("/", get(|| async { ApiError::TokenExpired }))
+            .layer(middleware::from_fn(request_id));
+
+        let request = Request::builder()
+            .uri("/")
+            .header("x-request-id", "01HQ3S5B8D0RWPWWZ0XQNV6T6Y")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let problem = problem(response).await;
+        assert_eq!(
+            problem,
+            Problem {
+                kind: "urn:permesi:genesis:token-expired".to_string(),
+                title: "Token expired".to_string(),
+                status: 403,
+                detail: "The token is too old to be verified".to_string(),
+                request_id: Some("01HQ3S5B8D0RWPWWZ0XQNV6T6Y".to_string()),
+            }
+        );
+    }
+}
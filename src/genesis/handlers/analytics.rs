@@ -0,0 +1,353 @@
+use crate::genesis::{
+    error::{ApiError, Problem},
+    state::AppState,
+    store::analytics::{self, Bucket, Range},
+};
+use anyhow::Context;
+use axum::{
+    extract::{rejection::QueryRejection, Query, State},
+    response::Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::instrument;
+use utoipa::{IntoParams, ToSchema};
+
+const DEFAULT_LIMIT: i64 = 10;
+const MAX_LIMIT: i64 = 100;
+
+#[derive(IntoParams, Debug, Deserialize, Default)]
+#[into_params(parameter_in = Query)]
+pub struct StatsArgs {
+    // RFC 3339, defaults to 24 hours before `to`
+    from: Option<String>,
+    // RFC 3339, defaults to now
+    to: Option<String>,
+    // minute, hour or day, defaults to hour
+    bucket: Option<String>,
+    // rows for the top countries and IPs, defaults to 10, max 100
+    limit: Option<i64>,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct ClientStats {
+    // start of the bucket, RFC 3339
+    bucket: String,
+    client: String,
+    issued: i64,
+    // null without --track-verified
+    verified: Option<i64>,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct TopStats {
+    // country code or IP address
+    value: String,
+    tokens: i64,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct VerifiedStats {
+    issued: i64,
+    verified: i64,
+    // verified / issued, 0 to 1
+    share: f64,
+}
+
+impl StatsArgs {
+    fn range(&self, now: DateTime<Utc>) -> Result<Range, ApiError> {
+        let parse = |value: &Option<String>, name: &str| {
+            value
+                .as_deref()
+                .map(|value| {
+                    DateTime::parse_from_rfc3339(value)
+                        .map(|time| time.with_timezone(&Utc))
+                        .map_err(|err| {
+                            ApiError::BadRequest(format!(
+                                "Invalid {name}, expected RFC 3339: {err}"
+                            ))
+                        })
+                })
+                .transpose()
+        };
+
+        let to = parse(&self.to, "to")?.unwrap_or(now);
+        let from = parse(&self.from, "from")?.unwrap_or(to - Duration::days(1));
+
+        let range = Range { from, to };
+
+        range
+            .validate(self.bucket()?)
+            .map_err(|err| ApiError::BadRequest(err.to_string()))?;
+
+        Ok(range)
+    }
+
+    fn bucket(&self) -> Result<Bucket, ApiError> {
+        match self.bucket.as_deref() {
+            None | Some("hour") => Ok(Bucket::Hour),
+            Some("minute") => Ok(Bucket::Minute),
+            Some("day") => Ok(Bucket::Day),
+            Some(bucket) => Err(ApiError::BadRequest(format!(
+                "Invalid bucket {bucket}, expected minute, hour or day"
+            ))),
+        }
+    }
+
+    fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+}
+
+// analytics read the tables directly, not available with the in-memory store
+fn pool(state: &AppState) -> Result<&PgPool, ApiError> {
+    state
+        .pool
+        .as_ref()
+        .ok_or_else(|| ApiError::NotImplemented("Analytics require the postgres store".to_string()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/admin/stats/clients",
+    params(StatsArgs),
+    responses (
+        (status = 200, description = "Tokens issued and verified per client and bucket", body = [ClientStats]),
+        (status = 400, description = "Invalid range or bucket", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Error querying the analytics", body = Problem, content_type = "application/problem+json"),
+        (status = 501, description = "Not available with the in-memory store", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Database credentials being renewed", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("mtls" = [])),
+    tag = "admin",
+)]
+#[instrument(skip(state))]
+pub async fn clients(
+    State(state): State<AppState>,
+    query: Result<Query<StatsArgs>, QueryRejection>,
+) -> Result<Json<Vec<ClientStats>>, ApiError> {
+    let Query(args) = query?;
+    let range = args.range(state.clock.now())?;
+    let bucket = args.bucket()?;
+
+    let stats = analytics::clients(
+        pool(&state)?,
+        state.config.schema_layout,
+        range,
+        bucket,
+        state.config.track_verified,
+    )
+    .await
+    .context("Failed to query analytics")
+    .map_err(|err| state.store_error(err))?;
+
+    Ok(Json(
+        stats
+            .into_iter()
+            .map(|usage| ClientStats {
+                bucket: usage.bucket.to_rfc3339(),
+                client: usage.client,
+                issued: usage.issued,
+                verified: usage.verified,
+            })
+            .collect(),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/admin/stats/countries",
+    params(StatsArgs),
+    responses (
+        (status = 200, description = "Countries with the most tokens", body = [TopStats]),
+        (status = 400, description = "Invalid range", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Error querying the analytics", body = Problem, content_type = "application/problem+json"),
+        (status = 501, description = "Not available with the in-memory store", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Database credentials being renewed", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("mtls" = [])),
+    tag = "admin",
+)]
+#[instrument(skip(state))]
+pub async fn countries(
+    State(state): State<AppState>,
+    query: Result<Query<StatsArgs>, QueryRejection>,
+) -> Result<Json<Vec<TopStats>>, ApiError> {
+    let Query(args) = query?;
+    let range = args.range(state.clock.now())?;
+
+    let top = analytics::countries(
+        pool(&state)?,
+        state.config.schema_layout,
+        range,
+        args.limit(),
+    )
+    .await
+    .context("Failed to query analytics")
+    .map_err(|err| state.store_error(err))?;
+
+    Ok(Json(top_stats(top)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/admin/stats/ips",
+    params(StatsArgs),
+    responses (
+        (status = 200, description = "IP addresses with the most tokens", body = [TopStats]),
+        (status = 400, description = "Invalid range", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Error querying the analytics", body = Problem, content_type = "application/problem+json"),
+        (status = 501, description = "Not available with the in-memory store", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Database credentials being renewed", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("mtls" = [])),
+    tag = "admin",
+)]
+#[instrument(skip(state))]
+pub async fn ips(
+    State(state): State<AppState>,
+    query: Result<Query<StatsArgs>, QueryRejection>,
+) -> Result<Json<Vec<TopStats>>, ApiError> {
+    let Query(args) = query?;
+    let range = args.range(state.clock.now())?;
+
+    let top = analytics::ips(
+        pool(&state)?,
+        state.config.schema_layout,
+        range,
+        args.limit(),
+    )
+    .await
+    .context("Failed to query analytics")
+    .map_err(|err| state.store_error(err))?;
+
+    Ok(Json(top_stats(top)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/admin/stats/verified",
+    params(StatsArgs),
+    responses (
+        (status = 200, description = "Share of the issued tokens that were verified", body = VerifiedStats),
+        (status = 400, description = "Invalid range", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Error querying the analytics", body = Problem, content_type = "application/problem+json"),
+        (status = 501, description = "Not available with the in-memory store or without --track-verified", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Database credentials being renewed", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("mtls" = [])),
+    tag = "admin",
+)]
+#[instrument(skip(state))]
+pub async fn verified(
+    State(state): State<AppState>,
+    query: Result<Query<StatsArgs>, QueryRejection>,
+) -> Result<Json<VerifiedStats>, ApiError> {
+    let Query(args) = query?;
+    let range = args.range(state.clock.now())?;
+
+    if !state.config.track_verified {
+        return Err(ApiError::NotImplemented(
+            "Verifications are not tracked, start genesis with --track-verified".to_string(),
+        ));
+    }
+
+    let verified = analytics::verified(pool(&state)?, state.config.schema_layout, range)
+        .await
+        .context("Failed to query analytics")
+        .map_err(|err| state.store_error(err))?;
+
+    Ok(Json(VerifiedStats {
+        issued: verified.issued,
+        verified: verified.verified,
+        share: verified.share(),
+    }))
+}
+
+fn top_stats(top: Vec<analytics::Top>) -> Vec<TopStats> {
+    top.into_iter()
+        .map(|top| TopStats {
+            value: top.key,
+            tokens: top.tokens,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::{clock::FixedClock, state};
+    use axum::{http::StatusCode, response::IntoResponse};
+    use chrono::TimeZone;
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 2, 23, 10, 46, 47).unwrap()
+    }
+
+    #[test]
+    fn test_args() {
+        let range = StatsArgs::default().range(now()).unwrap();
+        assert_eq!(range.to, now());
+        assert_eq!(range.from, now() - Duration::days(1));
+
+        let args = StatsArgs {
+            from: Some("2024-02-23T10:00:00Z".to_string()),
+            to: Some("2024-02-23T12:00:00+01:00".to_string()),
+            bucket: Some("minute".to_string()),
+            limit: Some(1000),
+        };
+        let range = args.range(now()).unwrap();
+        assert_eq!(
+            range.from,
+            Utc.with_ymd_and_hms(2024, 2, 23, 10, 0, 0).unwrap()
+        );
+        assert_eq!(
+            range.to,
+            Utc.with_ymd_and_hms(2024, 2, 23, 11, 0, 0).unwrap()
+        );
+        assert_eq!(args.limit(), MAX_LIMIT);
+    }
+
+    #[test]
+    fn test_args_invalid() {
+        let args = StatsArgs {
+            from: Some("yesterday".to_string()),
+            ..StatsArgs::default()
+        };
+        assert_eq!(
+            args.range(now()).unwrap_err().status(),
+            StatusCode::BAD_REQUEST
+        );
+
+        let args = StatsArgs {
+            bucket: Some("week".to_string()),
+            ..StatsArgs::default()
+        };
+        assert_eq!(
+            args.range(now()).unwrap_err().status(),
+            StatusCode::BAD_REQUEST
+        );
+
+        // 30 days of minutes
+        let args = StatsArgs {
+            from: Some("2024-01-24T00:00:00Z".to_string()),
+            bucket: Some("minute".to_string()),
+            ..StatsArgs::default()
+        };
+        assert_eq!(
+            args.range(now()).unwrap_err().status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        let state = state::tests::state(FixedClock(now()));
+
+        let response = verified(State(state), Ok(Query(StatsArgs::default())))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+    }
+}
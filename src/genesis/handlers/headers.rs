@@ -7,9 +7,9 @@ use tracing::instrument;
 
 #[utoipa::path(
     get,
-    path= "/headers",
+    path = "/v1/headers",
     responses (
-        (status = 200, description = "headers"),
+        (status = 200, description = "Request headers, one `name: value` per line", body = String, content_type = "text/plain"),
     ),
     tag = "headers",
 )]
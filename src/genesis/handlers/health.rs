@@ -1,14 +1,17 @@
-use crate::genesis::GIT_COMMIT_HASH;
+use crate::{
+    genesis::{state::AppState, GIT_COMMIT_HASH},
+    vault::status::{Snapshot, EXPIRING_THRESHOLD},
+};
 use axum::{
     body::Body,
-    extract::Extension,
+    extract::{Query, State},
     http::{HeaderMap, HeaderValue, Method, StatusCode},
     response::{IntoResponse, Json},
 };
 use serde::{Deserialize, Serialize};
 use sqlx::{Connection, PgPool};
 use tracing::{debug, error};
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
 
 #[derive(ToSchema, Serialize, Deserialize, Debug)]
 pub struct Health {
@@ -16,46 +19,111 @@ pub struct Health {
     name: String,
     version: String,
     database: String,
+    vault: VaultHealth,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subsystems: Option<Vec<Subsystem>>,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct VaultHealth {
+    // false in dev mode
+    enabled: bool,
+    token_valid: bool,
+    // seconds left on the token, null when unknown or managed by a Vault Agent
+    token_ttl: Option<i64>,
+    // seconds left on the DB lease
+    db_lease_ttl: Option<i64>,
+    // RFC 3339
+    last_renewal: Option<String>,
+    // token_renewal_failed or db_lease_renewal_failed, the error is in the logs
+    last_error: Option<String>,
+}
+
+impl From<&Snapshot> for VaultHealth {
+    fn from(snapshot: &Snapshot) -> Self {
+        Self {
+            enabled: snapshot.enabled,
+            token_valid: snapshot.token_valid,
+            token_ttl: snapshot.token_ttl(),
+            db_lease_ttl: snapshot.db_lease_ttl(),
+            last_renewal: snapshot.last_renewal.map(|t| t.to_rfc3339()),
+            last_error: snapshot.last_error.map(|f| f.as_str().to_string()),
+        }
+    }
+}
+
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct Subsystem {
+    name: String,
+    // ok, expiring, error or disabled
+    status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl Subsystem {
+    fn new(name: &str, status: &str, detail: Option<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            status: status.to_string(),
+            detail,
+        }
+    }
+}
+
+#[derive(IntoParams, Debug, Deserialize, Default)]
+#[into_params(parameter_in = Query)]
+pub struct HealthArgs {
+    // list the state of each subsystem
+    #[serde(default)]
+    detailed: bool,
 }
 
 #[utoipa::path(
-    get,
-    path= "/health",
+    method(get, options),
+    path = "/v1/health",
+    params(HealthArgs),
     responses (
-        (status = 200, description = "Database connection is healthy", body = [Health]),
-        (status = 503, description = "Database connection is unhealthy", body = [Health])
+        (status = 200, description = "Database connection is healthy, no body for OPTIONS", body = Health),
+        (status = 503, description = "Database connection is unhealthy or the server is draining, no body for OPTIONS", body = Health),
     ),
     tag = "health",
 )]
 // axum handler for health
-pub async fn health(method: Method, pool: Extension<PgPool>) -> impl IntoResponse {
-    let result = match pool.0.acquire().await {
-        Ok(mut conn) => match conn.ping().await {
-            Ok(()) => Ok(()),
-            Err(error) => {
-                error!("Failed to ping database: {}", error);
+pub async fn health(
+    method: Method,
+    query: Option<Query<HealthArgs>>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    // no database with the in-memory store
+    let database = match &state.pool {
+        Some(pool) => Some(ping(pool).await),
+        None => None,
+    };
 
-                Err(StatusCode::SERVICE_UNAVAILABLE)
-            }
-        },
+    let result = if database == Some(false) {
+        Err(StatusCode::SERVICE_UNAVAILABLE)
+    } else {
+        Ok(())
+    };
 
-        Err(error) => {
-            error!("Failed to acquire database connection: {}", error);
+    let vault = state.vault_status.snapshot();
 
-            Err(StatusCode::SERVICE_UNAVAILABLE)
-        }
-    };
+    let detailed = query.is_some_and(|Query(args)| args.detailed);
 
     // Create a health struct
     let health = Health {
         commit: GIT_COMMIT_HASH.to_string(),
         name: env!("CARGO_PKG_NAME").to_string(),
         version: env!("CARGO_PKG_VERSION").to_string(),
-        database: if result.is_ok() {
-            "ok".to_string()
-        } else {
-            "error".to_string()
-        },
+        database: match database {
+            Some(true) => "ok",
+            Some(false) => "error",
+            None => "disabled",
+        }
+        .to_string(),
+        vault: VaultHealth::from(&vault),
+        subsystems: detailed.then(|| subsystems(database, &vault)),
     };
 
     let body = if method == Method::GET {
@@ -64,30 +132,14 @@ pub async fn health(method: Method, pool: Extension<PgPool>) -> impl IntoRespons
         Body::empty().into_response()
     };
 
-    let short_hash = if health.commit.len() > 7 {
-        &health.commit[0..7]
-    } else {
-        ""
-    };
+    let headers = x_app_headers();
 
-    // Create headers using the map method
-    let headers = format!("{}:{}:{}", health.name, health.version, short_hash)
-        .parse::<HeaderValue>()
-        .map(|x_app_header_value| {
-            debug!("X-App header: {:?}", x_app_header_value);
-
-            let mut headers = HeaderMap::new();
-
-            headers.insert("X-App", x_app_header_value);
+    // Fail the check while draining so no new traffic is routed here
+    if state.lifecycle.is_draining() {
+        debug!("Server is draining");
 
-            headers
-        })
-        .map_err(|err| {
-            debug!("Failed to parse X-App header: {}", err);
-        });
-
-    // Unwrap the headers or provide a default value (empty headers) in case of an error
-    let headers = headers.unwrap_or_else(|()| HeaderMap::new());
+        return (StatusCode::SERVICE_UNAVAILABLE, headers, body);
+    }
 
     match result {
         Ok(()) => {
@@ -103,3 +155,158 @@ pub async fn health(method: Method, pool: Extension<PgPool>) -> impl IntoRespons
         }
     }
 }
+
+async fn ping(pool: &PgPool) -> bool {
+    match pool.acquire().await {
+        Ok(mut conn) => match conn.ping().await {
+            Ok(()) => true,
+            Err(error) => {
+                error!("Failed to ping database: {}", error);
+                false
+            }
+        },
+
+        Err(error) => {
+            error!("Failed to acquire database connection: {}", error);
+            false
+        }
+    }
+}
+
+/// `X-App: name:version:short_hash` header returned by the health checks
+pub fn x_app_headers() -> HeaderMap {
+    let short_hash = if GIT_COMMIT_HASH.len() > 7 {
+        &GIT_COMMIT_HASH[0..7]
+    } else {
+        ""
+    };
+
+    // Create headers using the map method
+    let headers = format!(
+        "{}:{}:{}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        short_hash
+    )
+    .parse::<HeaderValue>()
+    .map(|x_app_header_value| {
+        debug!("X-App header: {:?}", x_app_header_value);
+
+        let mut headers = HeaderMap::new();
+
+        headers.insert("X-App", x_app_header_value);
+
+        headers
+    })
+    .map_err(|err| {
+        debug!("Failed to parse X-App header: {}", err);
+    });
+
+    // Unwrap the headers or provide a default value (empty headers) in case of an error
+    headers.unwrap_or_else(|()| HeaderMap::new())
+}
+
+// state of each subsystem, tells "DB down" apart from "credentials about to expire"
+fn subsystems(database: Option<bool>, vault: &Snapshot) -> Vec<Subsystem> {
+    let database = match database {
+        Some(true) => Subsystem::new("database", "ok", None),
+        Some(false) => Subsystem::new("database", "error", Some("ping failed".to_string())),
+        None => Subsystem::new("database", "disabled", None),
+    };
+
+    if !vault.enabled {
+        return vec![
+            database,
+            Subsystem::new("vault_token", "disabled", None),
+            Subsystem::new("db_lease", "disabled", None),
+        ];
+    }
+
+    let token = if !vault.token_valid {
+        Subsystem::new(
+            "vault_token",
+            "error",
+            vault.last_error.map(|f| f.as_str().to_string()),
+        )
+    } else {
+        ttl_subsystem("vault_token", vault.token_ttl())
+    };
+
+    vec![
+        database,
+        token,
+        ttl_subsystem("db_lease", vault.db_lease_ttl()),
+    ]
+}
+
+fn ttl_subsystem(name: &str, ttl: Option<i64>) -> Subsystem {
+    match ttl {
+        Some(0) => Subsystem::new(name, "error", Some("expired".to_string())),
+        Some(ttl) if ttl < EXPIRING_THRESHOLD => {
+            Subsystem::new(name, "expiring", Some(format!("expires in {ttl} seconds")))
+        }
+        Some(ttl) => Subsystem::new(name, "ok", Some(format!("expires in {ttl} seconds"))),
+        None => Subsystem::new(name, "ok", None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::status::Failure;
+    use chrono::{Duration, Utc};
+
+    fn status_of<'a>(subsystems: &'a [Subsystem], name: &str) -> &'a str {
+        subsystems
+            .iter()
+            .find(|s| s.name == name)
+            .map(|s| s.status.as_str())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_subsystems() {
+        let vault = Snapshot {
+            enabled: true,
+            token_valid: true,
+            token_expires_at: Some(Utc::now() + Duration::seconds(3600)),
+            db_lease_expires_at: Some(Utc::now() + Duration::seconds(30)),
+            ..Snapshot::default()
+        };
+
+        let subsystems = subsystems(Some(false), &vault);
+        assert_eq!(status_of(&subsystems, "database"), "error");
+        assert_eq!(status_of(&subsystems, "vault_token"), "ok");
+        assert_eq!(status_of(&subsystems, "db_lease"), "expiring");
+    }
+
+    #[test]
+    fn test_subsystems_token_invalid() {
+        let vault = Snapshot {
+            enabled: true,
+            token_valid: false,
+            last_error: Some(Failure::TokenRenewal),
+            ..Snapshot::default()
+        };
+
+        let subsystems = subsystems(Some(true), &vault);
+        assert_eq!(status_of(&subsystems, "database"), "ok");
+        assert_eq!(status_of(&subsystems, "vault_token"), "error");
+
+        // the category, never the error itself
+        let token = subsystems.iter().find(|s| s.name == "vault_token").unwrap();
+        assert_eq!(token.detail.as_deref(), Some("token_renewal_failed"));
+        assert_eq!(
+            VaultHealth::from(&vault).last_error.as_deref(),
+            Some("token_renewal_failed")
+        );
+    }
+
+    #[test]
+    fn test_subsystems_disabled() {
+        let subsystems = subsystems(None, &Snapshot::default());
+        assert_eq!(status_of(&subsystems, "database"), "disabled");
+        assert_eq!(status_of(&subsystems, "vault_token"), "disabled");
+        assert_eq!(status_of(&subsystems, "db_lease"), "disabled");
+    }
+}
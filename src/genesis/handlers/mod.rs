@@ -9,3 +9,8 @@ pub use self::headers::headers;
 
 pub mod verify;
 pub use self::verify::verify;
+
+pub mod probes;
+pub use self::probes::{livez, readyz, startupz};
+
+pub mod analytics;
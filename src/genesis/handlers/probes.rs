@@ -0,0 +1,218 @@
+use crate::genesis::{handlers::health::x_app_headers, state::AppState};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Json},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Connection, PgPool, Row};
+use std::{collections::BTreeMap, time::Duration};
+use tokio::time::timeout;
+use tracing::{debug, error};
+use utoipa::ToSchema;
+
+/// Probes must answer before the kubelet gives up, never wait for the pool
+const CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(ToSchema, Serialize, Deserialize, Debug)]
+pub struct Probe {
+    // ok or error
+    status: String,
+    // name of the check and its result
+    checks: BTreeMap<String, String>,
+}
+
+impl Probe {
+    fn new(checks: BTreeMap<String, String>) -> Self {
+        let status = if checks.values().all(|v| v == "ok" || v == "disabled") {
+            "ok"
+        } else {
+            "error"
+        };
+
+        Self {
+            status: status.to_string(),
+            checks,
+        }
+    }
+
+    fn response(self) -> impl IntoResponse {
+        let status = if self.status == "ok" {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+
+        (status, x_app_headers(), Json(self))
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/livez",
+    responses (
+        (status = 200, description = "Process is responsive", body = Probe),
+    ),
+    tag = "health",
+)]
+// axum handler for the liveness probe, never touches the database so a DB
+// outage doesn't restart the pods
+pub async fn livez() -> impl IntoResponse {
+    Probe::new(BTreeMap::new()).response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/startupz",
+    responses (
+        (status = 200, description = "Startup completed", body = Probe),
+        (status = 503, description = "Still starting", body = Probe),
+    ),
+    tag = "health",
+)]
+// axum handler for the startup probe
+pub async fn startupz(State(state): State<AppState>) -> impl IntoResponse {
+    let mut checks = BTreeMap::new();
+
+    checks.insert(
+        "started".to_string(),
+        ok_or_error(state.lifecycle.is_started()),
+    );
+
+    Probe::new(checks).response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/readyz",
+    responses (
+        (status = 200, description = "Ready to receive traffic", body = Probe),
+        (status = 503, description = "Database, Vault or schema not ready, or draining", body = Probe),
+    ),
+    tag = "health",
+)]
+// axum handler for the readiness probe
+pub async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    let mut checks = BTreeMap::new();
+
+    checks.insert(
+        "started".to_string(),
+        ok_or_error(state.lifecycle.is_started()),
+    );
+    checks.insert(
+        "draining".to_string(),
+        ok_or_error(!state.lifecycle.is_draining()),
+    );
+
+    // no database with the in-memory store
+    if let Some(pool) = &state.pool {
+        let (database, schema) = check_database(pool, state.config.track_verified).await;
+        checks.insert("database".to_string(), ok_or_error(database));
+        checks.insert("schema".to_string(), ok_or_error(schema));
+    } else {
+        checks.insert("database".to_string(), "disabled".to_string());
+        checks.insert("schema".to_string(), "disabled".to_string());
+    }
+
+    let vault = state.vault_status.snapshot();
+    let vault = if !vault.enabled {
+        "disabled".to_string()
+    } else {
+        ok_or_error(vault.token_valid && vault.db_lease_ttl().unwrap_or(0) > 0)
+    };
+    checks.insert("vault".to_string(), vault);
+
+    let probe = Probe::new(checks);
+
+    debug!("Readiness: {:?}", probe);
+
+    probe.response()
+}
+
+fn ok_or_error(ok: bool) -> String {
+    if ok { "ok" } else { "error" }.to_string()
+}
+
+// columns added by the migrations, `tokens.verified_at` is only needed with
+// --track-verified
+fn required_columns(track_verified: bool) -> Vec<(&'static str, &'static str)> {
+    let mut columns = vec![("clients", "allowed_origins")];
+
+    if track_verified {
+        columns.push(("tokens", "verified_at"));
+    }
+
+    columns
+}
+
+// ping the database and check the tables and the columns exist, bounded by
+// CHECK_TIMEOUT
+async fn check_database(pool: &PgPool, track_verified: bool) -> (bool, bool) {
+    let (tables, columns): (Vec<_>, Vec<_>) = required_columns(track_verified).into_iter().unzip();
+
+    let check = async {
+        let mut conn = pool.acquire().await?;
+
+        conn.ping().await?;
+
+        let row = sqlx::query(
+            "SELECT to_regclass('clients') IS NOT NULL AND to_regclass('tokens') IS NOT NULL AND to_regclass('metadata') IS NOT NULL AS schema",
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+
+        if !row.get::<bool, _>("schema") {
+            return Ok(false);
+        }
+
+        let row = sqlx::query(
+            "SELECT bool_and(EXISTS (SELECT 1 FROM pg_attribute WHERE attrelid = to_regclass(c.tbl) AND attname = c.col AND NOT attisdropped)) AS columns FROM unnest($1::text[], $2::text[]) AS c(tbl, col)",
+        )
+        .bind(&tables)
+        .bind(&columns)
+        .fetch_one(&mut *conn)
+        .await?;
+
+        Ok::<bool, sqlx::Error>(row.get::<Option<bool>, _>("columns").unwrap_or(true))
+    };
+
+    match timeout(CHECK_TIMEOUT, check).await {
+        Ok(Ok(schema)) => (true, schema),
+        Ok(Err(err)) => {
+            error!("Database readiness check failed: {}", err);
+            (false, false)
+        }
+        Err(_) => {
+            error!("Database readiness check timed out");
+            (false, false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_status() {
+        let mut checks = BTreeMap::new();
+        checks.insert("database".to_string(), ok_or_error(true));
+        checks.insert("vault".to_string(), "disabled".to_string());
+        assert_eq!(Probe::new(checks.clone()).status, "ok");
+
+        checks.insert("draining".to_string(), ok_or_error(false));
+        assert_eq!(Probe::new(checks).status, "error");
+    }
+
+    #[test]
+    fn test_required_columns() {
+        assert_eq!(
+            required_columns(false),
+            vec![("clients", "allowed_origins")]
+        );
+        assert_eq!(
+            required_columns(true),
+            vec![("clients", "allowed_origins"), ("tokens", "verified_at")]
+        );
+    }
+}
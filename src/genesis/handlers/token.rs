@@ -1,25 +1,25 @@
+use crate::genesis::{
+    clock::Clock,
+    cors::RequestClient,
+    error::{ApiError, Problem},
+    state::AppState,
+    store::{Client, Metadata, TokenStore},
+};
+use anyhow::Context;
 use axum::{
-    extract::{Extension, Query},
-    http::{HeaderMap, StatusCode},
-    response::IntoResponse,
-    Json,
+    extract::{Query, State},
+    http::HeaderMap,
+    Extension, Json,
 };
-use chrono::{Duration, Utc};
-use serde::{Deserialize, Serialize};
-use sqlx::{postgres::PgDatabaseError, PgPool, Row};
-use std::{env, net::IpAddr, process};
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use std::time::SystemTime;
 use tracing::{debug, error, instrument};
 use ulid::Ulid;
-use utoipa::{IntoParams, ToSchema};
+use utoipa::IntoParams;
 use uuid::Uuid;
 
-pub const TOKEN_EXPIRATION: i64 = 120; // 2 minutes
-
-#[derive(ToSchema, Serialize, Deserialize, Debug)]
-pub struct Token {
-    token: String,
-    expires: i64,
-}
+pub use permesi_genesis_client::{Token, TOKEN_EXPIRATION};
 
 #[derive(IntoParams, Debug, Deserialize, Default)]
 #[into_params(parameter_in = Query)]
@@ -30,168 +30,254 @@ pub struct ClientArgs {
 
 #[utoipa::path(
     get,
-    path= "/token",
+    path = "/v1/token",
     params(ClientArgs),
     responses (
-        (status = 200, description = "Return token", body = [Token]),
-        (status = 500, description = "Error creating the token")
+        (status = 200, description = "Return token", body = Token),
+        (status = 400, description = "Missing or invalid client_id", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Error creating the token", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Database credentials being renewed", body = Problem, content_type = "application/problem+json"),
     ),
     tag = "token",
 )]
-#[instrument(skip(pool, headers, query))]
+#[instrument(skip(state, headers, query, client))]
 pub async fn token(
-    Extension(pool): Extension<PgPool>,
+    State(state): State<AppState>,
     headers: HeaderMap,
     query: Option<Query<ClientArgs>>,
-) -> impl IntoResponse {
-    let args = if let Some(args) = query {
-        Query(args)
-    } else {
+    client: Option<Extension<RequestClient>>,
+) -> Result<Json<Token>, ApiError> {
+    let Some(Query(args)) = query else {
         error!("Failed to get query parameters");
-        return Err((StatusCode::BAD_REQUEST, "Missing Client ID".to_string()));
+        return Err(ApiError::BadRequest(
+            "Missing client_id query parameter".to_string(),
+        ));
     };
 
-    let client_uuid = match args.client_id.parse::<Uuid>() {
-        Ok(uuid) => uuid,
-        Err(err) => {
-            error!("Failed to parse uuid: {}", err);
-            return Err((
-                StatusCode::BAD_REQUEST,
-                "Invalid Client ID format".to_string(),
-            ));
-        }
-    };
+    let client_uuid = args.client_id.parse::<Uuid>().map_err(|err| {
+        error!("Failed to parse uuid: {}", err);
+        ApiError::BadRequest("Invalid client_id, expected a UUID".to_string())
+    })?;
 
     debug!("Client UUID: {}", client_uuid);
 
-    let token = Ulid::new();
+    let metadata = Metadata::from_headers(&state.config.headers, &headers);
 
-    // Get the IP address from the headers using the environment variable if it exists
-    // otherwise use default to CF-Connecting-IP
-    let ip_address = ip_from_headers(
-        env::var("GENESIS_COUNTRY_HEADER").unwrap_or_else(|_| "CF-Connecting-IP".to_string()),
-        &headers,
-    );
+    let token = async {
+        // looked up by the CORS middleware, again if that lookup failed
+        let client = match client {
+            Some(Extension(RequestClient(client))) => client,
+            None => state
+                .store
+                .lookup_client(client_uuid)
+                .await
+                .context("Failed to retrieve client ID")?,
+        };
 
-    // Get the country from the headers using the environment variable if it exists
-    let country = headers
-        .get(env::var("GENESIS_COUNTRY_HEADER").unwrap_or_else(|_| "CF-IPCountry".to_string()))
-        .map(|country| country.to_str().ok());
+        issue(
+            state.store.as_ref(),
+            state.clock.as_ref(),
+            client_id(client_uuid, client),
+            &metadata,
+        )
+        .await
+    }
+    .await;
 
-    // User-Agent is optional
-    let ua = headers.get("User-Agent").and_then(|v| v.to_str().ok());
+    state.metrics.issued(token.is_ok());
 
-    // get client id from the payload
-    let query = "SELECT id FROM clients WHERE uuid = $1";
-    let client_id = match sqlx::query(query).bind(client_uuid).fetch_one(&pool).await {
-        Ok(row) => row.try_get::<i16, _>("id").unwrap_or_else(|err| {
-            error!("Failed to retrieve client ID or convert ID to i16: {}", err);
-            0
-        }),
-
-        Err(err) => {
-            match err {
-                sqlx::Error::Database(db_err)
-                    if db_err
-                        .as_error()
-                        .downcast_ref::<PgDatabaseError>()
-                        .map(PgDatabaseError::code)
-                        == Some("42501") =>
-                {
-                    // will terminate the program with exit code 1 and helps to get a new vault
-                    // token, usful when modifying the DB schema
-                    error!(
-                        "DB Error 42501 - Insufficient privilege: {}",
-                        db_err.message()
-                    );
-                    process::exit(1); // Terminate the program with exit code 1
-                }
-                _ => {
-                    debug!("Failed to retrieve client ID from database: {}", err);
-                }
-            }
+    Ok(Json(token.map_err(|err| state.store_error(err))?))
+}
+
+/// Expiration of a token issued at `issued_at`
+#[must_use]
+pub fn expires_at(issued_at: DateTime<Utc>) -> DateTime<Utc> {
+    issued_at + Duration::try_seconds(TOKEN_EXPIRATION).expect("Failed to create expiration time")
+}
+
+// unknown clients get the id 0, a failed lookup fails the request so the
+// token is not counted for the wrong client
+fn client_id(client_uuid: Uuid, client: Option<Client>) -> i16 {
+    client.map_or_else(
+        || {
+            debug!("Client not found: {}", client_uuid);
             0
-        }
-    };
+        },
+        |client| client.id,
+    )
+}
+
+/// Create a token for the client, the ULID timestamp is the time it was issued
+/// # Errors
+/// Will return an error if the token can't be stored
+pub async fn issue(
+    store: &dyn TokenStore,
+    clock: &dyn Clock,
+    client_id: i16,
+    metadata: &Metadata,
+) -> anyhow::Result<Token> {
+    let issued_at = clock.now();
+
+    let token = Ulid::from_datetime(SystemTime::from(issued_at));
 
     debug!("Client ID: {}", client_id);
 
-    // start transaction
-    let mut tx = match pool.begin().await {
-        Ok(tx) => tx,
-        Err(err) => {
-            error!("Failed to start transaction: {}", err);
+    store.insert(&token, client_id, metadata).await?;
 
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to start transaction".to_string(),
-            ));
-        }
+    Ok(Token {
+        token: token.to_string(),
+        expires: expires_at(issued_at).timestamp(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::{
+        clock::FixedClock,
+        handlers::verify::{self, check},
+        state,
+        store::MemoryStore,
     };
+    use anyhow::{anyhow, Result};
+    use async_trait::async_trait;
+    use axum::{
+        http::{HeaderValue, StatusCode},
+        response::IntoResponse,
+    };
+    use chrono::Utc;
+    use std::sync::Arc;
 
-    let query = "INSERT INTO tokens (id, client_id) VALUES ($1::ulid, $2) RETURNING id::text";
-    let result = match sqlx::query(query)
-        .bind(token.to_string())
-        .bind(client_id)
-        .fetch_one(&mut *tx)
-        .await
-    {
-        Ok(row) => {
-            let token_id: String = row.get("id");
-
-            let metadata_query =
-                "INSERT INTO metadata (id, ip_address, country, user_agent) VALUES ($1::ulid, $2, $3, $4)";
-            sqlx::query(metadata_query)
-                .bind(token_id)
-                .bind(ip_address)
-                .bind(country)
-                .bind(ua)
-                .execute(&mut *tx)
-                .await
+    // the client lookup fails, e.g. the connection dropped
+    #[derive(Debug)]
+    struct BrokenClients(MemoryStore);
+
+    #[async_trait]
+    impl TokenStore for BrokenClients {
+        async fn lookup_client(&self, _uuid: Uuid) -> Result<Option<Client>> {
+            Err(anyhow!("connection reset"))
         }
 
-        Err(err) => Err(err),
-    };
+        async fn insert(&self, token: &Ulid, client_id: i16, metadata: &Metadata) -> Result<()> {
+            self.0.insert(token, client_id, metadata).await
+        }
 
-    match result {
-        Ok(_) => match tx.commit().await {
-            Ok(()) => {
-                let expiration_time = Utc::now()
-                    + Duration::try_seconds(TOKEN_EXPIRATION)
-                        .expect("Failed to create expiration time");
+        async fn verify(&self, token: &Ulid) -> Result<bool> {
+            self.0.verify(token).await
+        }
 
-                let token = Token {
-                    token: token.to_string(),
-                    expires: expiration_time.timestamp(),
-                };
+        async fn record_verified(&self, token: &Ulid) -> Result<()> {
+            self.0.record_verified(token).await
+        }
 
-                Ok((StatusCode::OK, Json(token)))
-            }
+        async fn consume(&self, token: &Ulid) -> Result<bool> {
+            self.0.consume(token).await
+        }
 
-            Err(err) => {
-                error!("Failed to commit transaction: {}", err);
+        async fn revoke(&self, token: &Ulid) -> Result<bool> {
+            self.0.revoke(token).await
+        }
+    }
 
-                Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
-            }
-        },
+    #[test]
+    fn test_expires_at() {
+        let now = Utc::now();
+        assert_eq!((expires_at(now) - now).num_seconds(), TOKEN_EXPIRATION);
+    }
 
-        Err(err) => {
-            match tx.rollback().await {
-                Ok(()) => debug!("Rolled back transaction"),
+    #[tokio::test]
+    async fn test_token_bad_request() {
+        let state = state::tests::state(FixedClock(Utc::now()));
 
-                Err(err) => error!("Failed to rollback transaction: {}", err),
-            };
+        let response = token(State(state.clone()), HeaderMap::new(), None, None)
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
 
-            error!("Failed to insert token into database: {}", err);
+        let query = Query(ClientArgs {
+            client_id: "nope".to_string(),
+        });
+        let response = token(State(state), HeaderMap::new(), Some(query), None)
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
 
-            Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
-        }
+    #[tokio::test]
+    async fn test_token() {
+        let now = Utc::now();
+        let store = Arc::new(MemoryStore::new(std::time::Duration::from_secs(120)));
+        let state = state::tests::with_store(FixedClock(now), store.clone());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("CF-IPCountry", HeaderValue::from_static("CH"));
+
+        let query = Query(ClientArgs {
+            client_id: Uuid::nil().to_string(),
+        });
+        let response = token(State(state.clone()), headers, Some(query), None)
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let issued: Token = serde_json::from_slice(&body).unwrap();
+        assert_eq!(issued.expires, expires_at(now).timestamp());
+
+        // the token is stored with its metadata and can be verified
+        let ulid = check(&issued.token, now).unwrap();
+        assert_eq!(
+            store.metadata(&ulid).and_then(|(_, m)| m.country),
+            Some("CH".to_string())
+        );
+
+        let response = verify::verify(
+            State(state),
+            Ok(Json(verify::VerifyRequest {
+                token: issued.token,
+            })),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
     }
-}
 
-fn ip_from_headers(header: String, headers: &HeaderMap) -> Option<IpAddr> {
-    headers
-        .get(header)
-        .and_then(|hv| hv.to_str().ok())
-        .and_then(|s| s.parse::<IpAddr>().ok())
+    #[tokio::test]
+    async fn test_token_lookup_client_fails() {
+        let store = Arc::new(BrokenClients(MemoryStore::new(
+            std::time::Duration::from_secs(120),
+        )));
+        let state = state::tests::with_store(FixedClock(Utc::now()), store);
+
+        let query = Query(ClientArgs {
+            client_id: Uuid::nil().to_string(),
+        });
+        let response = token(State(state), HeaderMap::new(), Some(query), None)
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_token_request_client() {
+        let store = Arc::new(BrokenClients(MemoryStore::new(
+            std::time::Duration::from_secs(120),
+        )));
+        let state = state::tests::with_store(FixedClock(Utc::now()), store);
+
+        // the client looked up by the middleware is used, not looked up again
+        let query = Query(ClientArgs {
+            client_id: Uuid::nil().to_string(),
+        });
+        let client = Extension(RequestClient(Some(Client {
+            id: 7,
+            allowed_origins: Vec::new(),
+        })));
+        let response = token(State(state), HeaderMap::new(), Some(query), Some(client))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
 }
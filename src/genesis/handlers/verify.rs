@@ -1,57 +1,189 @@
-use crate::genesis::handlers::token::TOKEN_EXPIRATION;
-use axum::{extract::Extension, http::StatusCode, response::IntoResponse, Json};
-use serde::{Deserialize, Serialize};
-use sqlx::{PgPool, Row};
-use tracing::{debug, error, instrument};
+use crate::genesis::{
+    error::{ApiError, Problem},
+    handlers::token::expires_at,
+    state::AppState,
+};
+use axum::{
+    extract::{rejection::JsonRejection, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use tracing::{debug, error, instrument, warn};
 use ulid::Ulid;
-use utoipa::ToSchema;
 
-#[derive(ToSchema, Serialize, Deserialize, Debug)]
-pub struct Token {
-    token: String,
-}
+pub use permesi_genesis_client::VerifyRequest;
 
 #[utoipa::path(
     post,
-    path= "/verify",
+    path = "/v1/verify",
+    request_body = VerifyRequest,
     responses (
-        (status = 202, description = "Return token", body = [Token], content_type = "application/json"),
-        (status = 403, description = "Token expired or invalid"),
+        (status = 202, description = "Token is valid"),
+        (status = 400, description = "Malformed request or token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Token expired or invalid", body = Problem, content_type = "application/problem+json"),
+        (status = 500, description = "Error verifying the token", body = Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Database credentials being renewed", body = Problem, content_type = "application/problem+json"),
     ),
+    security((), ("mtls" = [])),
     tag = "verify",
 )]
-#[instrument]
-pub async fn verify(Extension(pool): Extension<PgPool>, payload: Json<Token>) -> impl IntoResponse {
-    let token = &payload.token;
-
-    match Ulid::from_string(token) {
-        Ok(_) => (),
-        Err(e) => {
-            error!("Error while parsing token: {}", e);
-            return StatusCode::BAD_REQUEST;
+#[instrument(skip(state))]
+pub async fn verify(
+    State(state): State<AppState>,
+    payload: Result<Json<VerifyRequest>, JsonRejection>,
+) -> Result<StatusCode, ApiError> {
+    let result = verify_token(&state, payload).await;
+
+    state.metrics.verified(
+        result
+            .as_ref()
+            .map_or_else(ApiError::status, |status| *status),
+    );
+
+    result
+}
+
+async fn verify_token(
+    state: &AppState,
+    payload: Result<Json<VerifyRequest>, JsonRejection>,
+) -> Result<StatusCode, ApiError> {
+    let Json(payload) = payload?;
+
+    let token = check(&payload.token, state.clock.now())?;
+
+    if state
+        .store
+        .verify(&token)
+        .await
+        .map_err(|err| state.store_error(err))?
+    {
+        debug!("Token is valid");
+
+        // off the response path, a failure only loses the statistic
+        if state.config.track_verified {
+            let store = state.store.clone();
+
+            tokio::spawn(async move {
+                if let Err(err) = store.record_verified(&token).await {
+                    warn!("Failed to record the verification: {:#}", err);
+                }
+            });
         }
+
+        Ok(StatusCode::ACCEPTED)
+    } else {
+        error!("Token is invalid");
+
+        Err(ApiError::InvalidToken)
     }
+}
 
-    let query = format!("SELECT EXISTS(SELECT 1 FROM tokens WHERE id = $1::ulid AND id::timestamp > NOW() - INTERVAL '{TOKEN_EXPIRATION} seconds') AS valid");
+/// Parse the token and check it has not expired, the ULID timestamp is the
+/// time it was issued
+/// # Errors
+/// `MalformedToken` if the token is not a ULID, `TokenExpired` if it expired
+pub fn check(token: &str, now: DateTime<Utc>) -> Result<Ulid, ApiError> {
+    let token = Ulid::from_string(token).map_err(|e| {
+        error!("Error while parsing token: {}", e);
+        ApiError::MalformedToken
+    })?;
 
-    match sqlx::query(&query).bind(token).fetch_one(&pool).await {
-        Ok(row) => {
-            let valid: bool = row.get("valid");
-            if valid {
-                debug!("Token is valid");
+    if now > expires_at(DateTime::<Utc>::from(token.datetime())) {
+        error!("Token is expired");
 
-                StatusCode::ACCEPTED
-            } else {
-                error!("Token is invalid");
+        return Err(ApiError::TokenExpired);
+    }
 
-                StatusCode::FORBIDDEN
-            }
-        }
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cli::config::Config,
+        genesis::{
+            clock::FixedClock,
+            handlers::token::TOKEN_EXPIRATION,
+            state,
+            store::{MemoryStore, Metadata, TokenStore},
+        },
+    };
+    use axum::response::IntoResponse;
+    use chrono::Duration;
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_check() {
+        let issued_at = Utc::now();
+        let token = Ulid::from_datetime(SystemTime::from(issued_at)).to_string();
+
+        assert!(check(&token, issued_at).is_ok());
+        assert!(check(&token, issued_at + Duration::seconds(TOKEN_EXPIRATION - 1)).is_ok());
+        assert!(matches!(
+            check(&token, issued_at + Duration::seconds(TOKEN_EXPIRATION + 1)),
+            Err(ApiError::TokenExpired)
+        ));
+        assert!(matches!(
+            check("nope", issued_at),
+            Err(ApiError::MalformedToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_verify_expired() {
+        // the database is never reached for expired tokens
+        let issued_at = Utc::now() - Duration::seconds(TOKEN_EXPIRATION + 1);
+        let token = Ulid::from_datetime(SystemTime::from(issued_at)).to_string();
 
-        Err(e) => {
-            error!("Error while verifying token: {}", e);
+        let state = state::tests::state(FixedClock(Utc::now()));
 
-            StatusCode::INTERNAL_SERVER_ERROR
+        let response = verify(State(state), Ok(Json(VerifyRequest { token })))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_verify_track_verified() {
+        let store = Arc::new(MemoryStore::new(std::time::Duration::from_secs(120)));
+        let token = Ulid::new();
+        store.insert(&token, 0, &Metadata::default()).await.unwrap();
+
+        let request = || {
+            Ok(Json(VerifyRequest {
+                token: token.to_string(),
+            }))
+        };
+
+        // read only by default
+        let state = state::tests::with_store(FixedClock(Utc::now()), store.clone());
+        let response = verify(State(state.clone()), request())
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        tokio::task::yield_now().await;
+        assert!(store.verified_at(&token).is_none());
+
+        let state = AppState {
+            config: Arc::new(Config {
+                track_verified: true,
+                ..Config::default()
+            }),
+            ..state
+        };
+        let response = verify(State(state), request()).await.into_response();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        // recorded by a task after the response
+        for _ in 0..100 {
+            if store.verified_at(&token).is_some() {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
         }
+        panic!("verification not recorded");
     }
 }
@@ -0,0 +1,58 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Process lifecycle flags shared between the server and its handlers
+#[derive(Debug, Default)]
+pub struct Lifecycle {
+    started: AtomicBool,
+    draining: AtomicBool,
+}
+
+impl Lifecycle {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mark the startup as completed, the server is listening
+    pub fn mark_started(&self) {
+        self.started.store(true, Ordering::SeqCst);
+    }
+
+    #[must_use]
+    pub fn is_started(&self) -> bool {
+        self.started.load(Ordering::SeqCst)
+    }
+
+    /// Mark the server as draining, readiness checks will start failing
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    #[must_use]
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_started() {
+        let lifecycle = Lifecycle::new();
+        assert!(!lifecycle.is_started());
+
+        lifecycle.mark_started();
+        assert!(lifecycle.is_started());
+    }
+
+    #[test]
+    fn test_draining() {
+        let lifecycle = Lifecycle::new();
+        assert!(!lifecycle.is_draining());
+
+        lifecycle.start_draining();
+        assert!(lifecycle.is_draining());
+    }
+}
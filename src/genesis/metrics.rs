@@ -0,0 +1,148 @@
+use crate::vault::status::Status;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::{
+    global,
+    metrics::{Counter, Histogram, ObservableGauge},
+    KeyValue,
+};
+use std::{fmt, sync::Arc, time::Instant};
+
+/// Instruments recorded by the handlers, no-ops unless metrics are exported
+pub struct Metrics {
+    tokens_issued: Counter<u64>,
+    tokens_verified: Counter<u64>,
+    request_duration: Histogram<f64>,
+    // observed on every collection, kept to tie their lifetime to the state
+    _vault: Vec<ObservableGauge<i64>>,
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Metrics {
+    /// Create the instruments from the global meter provider, call after
+    /// `telemetry::init`
+    #[must_use]
+    pub fn new(vault_status: Arc<Status>) -> Self {
+        let meter = global::meter(env!("CARGO_PKG_NAME"));
+
+        let gauge = |name: &'static str,
+                     description: &'static str,
+                     unit: &'static str,
+                     value: fn(&Status) -> Option<i64>| {
+            let status = vault_status.clone();
+
+            meter
+                .i64_observable_gauge(name)
+                .with_description(description)
+                .with_unit(unit)
+                .with_callback(move |observer| {
+                    if let Some(value) = value(&status) {
+                        observer.observe(value, &[]);
+                    }
+                })
+                .build()
+        };
+
+        Self {
+            tokens_issued: meter
+                .u64_counter("genesis.tokens.issued")
+                .with_description("Tokens issued, by result")
+                .build(),
+            tokens_verified: meter
+                .u64_counter("genesis.tokens.verified")
+                .with_description("Tokens verified, by result")
+                .build(),
+            request_duration: meter
+                .f64_histogram("http.server.request.duration")
+                .with_description("Duration of the HTTP requests")
+                .with_unit("s")
+                .build(),
+            _vault: vec![
+                gauge(
+                    "genesis.vault.token.valid",
+                    "1 if the Vault token is valid, not reported in dev mode",
+                    "",
+                    |status| {
+                        let snapshot = status.snapshot();
+                        snapshot.enabled.then(|| i64::from(snapshot.token_valid))
+                    },
+                ),
+                gauge(
+                    "genesis.vault.token.ttl",
+                    "Seconds left on the Vault token",
+                    "s",
+                    |status| status.snapshot().token_ttl(),
+                ),
+                gauge(
+                    "genesis.vault.db_lease.ttl",
+                    "Seconds left on the database lease",
+                    "s",
+                    |status| status.snapshot().db_lease_ttl(),
+                ),
+            ],
+        }
+    }
+
+    pub fn issued(&self, ok: bool) {
+        self.tokens_issued.add(
+            1,
+            &[KeyValue::new("result", if ok { "ok" } else { "error" })],
+        );
+    }
+
+    /// Record the outcome of `/verify` from its status code
+    pub fn verified(&self, status: StatusCode) {
+        let result = match status {
+            StatusCode::ACCEPTED => "valid",
+            StatusCode::FORBIDDEN => "rejected",
+            StatusCode::BAD_REQUEST => "malformed",
+            _ => "error",
+        };
+
+        self.tokens_verified
+            .add(1, &[KeyValue::new("result", result)]);
+    }
+
+    fn request(&self, method: String, route: String, status: StatusCode, seconds: f64) {
+        self.request_duration.record(
+            seconds,
+            &[
+                KeyValue::new("http.request.method", method),
+                KeyValue::new("http.route", route),
+                KeyValue::new("http.response.status_code", i64::from(status.as_u16())),
+            ],
+        );
+    }
+}
+
+/// Middleware recording the duration of the requests per route
+pub async fn track(State(metrics): State<Arc<Metrics>>, request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = request.method().to_string();
+
+    // the route template, not the path, to keep the cardinality low
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |path| path.as_str().to_string());
+
+    let response = next.run(request).await;
+
+    metrics.request(
+        method,
+        route,
+        response.status(),
+        start.elapsed().as_secs_f64(),
+    );
+
+    response
+}
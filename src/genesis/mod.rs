@@ -1,33 +1,68 @@
 use crate::{
-    cli::globals::GlobalArgs,
+    cli::{
+        config::Config,
+        globals::GlobalArgs,
+        telemetry::{logs, propagation},
+    },
     genesis::handlers::{
-        headers::__path_headers, health, health::__path_health, token, token::__path_token,
+        analytics,
+        analytics::{__path_clients, __path_countries, __path_ips, __path_verified},
+        headers::__path_headers,
+        health,
+        health::__path_health,
+        probes,
+        probes::{__path_livez, __path_readyz, __path_startupz},
+        token,
+        token::__path_token,
+        verify,
+        verify::__path_verify,
+    },
+    genesis::{
+        clock::SystemClock,
+        lifecycle::Lifecycle,
+        metrics::Metrics,
+        mtls::{ClientIdentity, MtlsAcceptor},
+        state::AppState,
+        store::{archive, partitions, postgres::Layout, MemoryStore, PgStore, TokenStore},
+        supervisor::Supervisor,
     },
     vault,
 };
 use anyhow::{Context, Result};
 use axum::{
     body::Body,
-    http::{HeaderName, HeaderValue, Method, Request},
+    http::{HeaderName, HeaderValue, Request},
+    middleware,
     routing::{get, post},
-    Extension, Router,
+    Router,
 };
+use axum_server::Handle;
+use chrono::Utc;
 use sqlx::postgres::PgPoolOptions;
-use std::time::Duration;
-use tokio::{net::TcpListener, sync::mpsc};
+use std::{fs, sync::Arc, time::Duration};
+use tokio::sync::{mpsc, watch};
 use tower::ServiceBuilder;
 use tower_http::{
-    cors::{Any, CorsLayer},
-    request_id::PropagateRequestIdLayer,
-    set_header::SetRequestHeaderLayer,
-    trace::TraceLayer,
+    request_id::PropagateRequestIdLayer, set_header::SetRequestHeaderLayer, trace::TraceLayer,
 };
-use tracing::{debug_span, info, Span};
+use tracing::{debug_span, info, warn, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use ulid::Ulid;
-use utoipa::OpenApi;
+use utoipa::{openapi::security::SecurityScheme, Modify, OpenApi};
 use utoipa_swagger_ui::SwaggerUi;
 
+pub mod clock;
+pub mod cors;
+pub mod error;
 mod handlers;
+pub mod lifecycle;
+pub mod metrics;
+pub mod mtls;
+mod shutdown;
+pub mod state;
+pub mod store;
+pub mod supervisor;
+pub mod tls;
 
 pub mod built_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
@@ -39,89 +74,421 @@ pub const GIT_COMMIT_HASH: &str = if let Some(hash) = built_info::GIT_COMMIT_HAS
     ":-("
 };
 
+/// Prefix of the versioned API, the unversioned paths are kept as aliases
+pub const API_PREFIX: &str = "/v1";
+
 #[derive(OpenApi)]
 #[openapi(
-    paths(health, headers, token),
+    info(description = "Token Zero generator, every path is also served without the `/v1` prefix"),
+    paths(health, livez, readyz, startupz, headers, token, verify, clients, countries, ips, verified),
     components(
-        schemas(health::Health, token::Token)
+        schemas(error::Problem, health::Health, health::VaultHealth, health::Subsystem, probes::Probe, token::Token, verify::VerifyRequest,
+            analytics::ClientStats, analytics::TopStats, analytics::VerifiedStats)
     ),
+    modifiers(&SecurityAddon),
     tags(
-        (name = "genesis", description = "Token Zero generator API"),
+        (name = "token", description = "Issue a Token Zero"),
+        (name = "verify", description = "Verify a Token Zero, on the mTLS listener when configured"),
+        (name = "admin", description = "Analytics of the issued tokens, mTLS only outside dev mode"),
+        (name = "health", description = "Health and Kubernetes probes"),
+        (name = "headers", description = "Debug the headers received by genesis"),
     )
-
 )]
 struct ApiDoc;
 
+// client certificates of the mTLS listener
+struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi.components.get_or_insert_with(Default::default).add_security_scheme(
+            "mtls",
+            SecurityScheme::MutualTls {
+                description: Some(
+                    "Client certificate signed by the mTLS client CA, required when mTLS is configured"
+                        .to_string(),
+                ),
+                extensions: None,
+            },
+        );
+    }
+}
+
+/// The `OpenAPI` spec, as written by `genesis openapi`
+/// # Errors
+/// Will return an error if the spec can't be serialized
+pub fn openapi() -> Result<String> {
+    let mut spec = ApiDoc::openapi()
+        .to_pretty_json()
+        .context("Failed to serialize the OpenAPI spec")?;
+
+    spec.push('\n');
+
+    Ok(spec)
+}
+
+// serve the routes under the API prefix and, as aliases, without it
+fn versioned(router: Router<AppState>) -> Router<AppState> {
+    Router::new().nest(API_PREFIX, router.clone()).merge(router)
+}
+
+/// Path without the API prefix, `/v1/token` and `/token` are the same route
+#[must_use]
+pub fn unversioned(path: &str) -> &str {
+    path.strip_prefix(API_PREFIX)
+        .filter(|rest| rest.starts_with('/'))
+        .unwrap_or(path)
+}
+
 /// router
 /// # Errors
 /// Returns an error if the server fails to start
-pub async fn new(port: u16, dsn: String, globals: &GlobalArgs) -> Result<()> {
+pub async fn new(config: Config, dsn: Option<String>, globals: &GlobalArgs) -> Result<()> {
     // Renew vault token, gracefully shutdown if failed
-    let (tx, mut rx) = mpsc::unbounded_channel();
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    // the DB lease to renew, replaced when the supervisor fetches new credentials
+    let (db_lease, db_lease_rx) = watch::channel(globals.vault_db_lease_id.clone());
+
+    let vault_status = if globals.dev {
+        warn!("Dev mode: Vault token and DB lease renewal are disabled");
+
+        Arc::new(vault::status::Status::disabled())
+    } else {
+        let vault_status = Arc::new(vault::status::Status::new(globals.vault_db_lease_duration));
 
-    vault::renew::try_renew(globals, tx).await?;
+        vault::renew::try_renew(globals, tx.clone(), vault_status.clone(), db_lease_rx).await?;
 
-    // Connect to database
-    let pool = PgPoolOptions::new()
-        .min_connections(1)
-        .max_connections(5)
-        .max_lifetime(Duration::from_secs(60 * 2))
-        .test_before_acquire(true)
-        .connect(&dsn)
-        .await
-        .context("Failed to connect to database")?;
+        vault_status
+    };
+
+    // Connect to database, no DSN with the in-memory store
+    let pool = match dsn {
+        Some(dsn) => Some(
+            PgPoolOptions::new()
+                .min_connections(1)
+                .max_connections(5)
+                .max_lifetime(supervisor::POOL_MAX_LIFETIME)
+                .test_before_acquire(true)
+                .connect(&dsn)
+                .await
+                .context("Failed to connect to database")?,
+        ),
+        None => None,
+    };
+
+    let expiration = Duration::from_secs(handlers::token::TOKEN_EXPIRATION.unsigned_abs());
+
+    // create the partitions before serving, then keep them up to date
+    if let (Some(pool), Layout::Partitioned) = (&pool, config.schema_layout) {
+        let archive = config.archive.is_some();
+
+        partitions::maintain(pool, &config.partitions, archive, Utc::now())
+            .await
+            .context("Failed to create the partitions")?;
+
+        partitions::spawn(pool.clone(), config.partitions.clone(), archive);
+    }
+
+    // archive the tokens older than the retention and delete them
+    if let (Some(pool), Some(archive)) = (&pool, &config.archive) {
+        fs::create_dir_all(&archive.dir)
+            .with_context(|| format!("Failed to create archive dir {}", archive.dir.display()))?;
+
+        archive::spawn(
+            pool.clone(),
+            config.schema_layout,
+            archive.clone(),
+            config.track_verified,
+        );
+    }
+
+    // requests report invalid DB credentials instead of exiting
+    let (supervisor, supervisor_rx) = Supervisor::new();
+
+    if let Some(pool) = &pool {
+        supervisor::spawn(
+            supervisor_rx,
+            pool.clone(),
+            globals.clone(),
+            vault_status.clone(),
+            db_lease.clone(),
+            tx,
+        );
+    }
+
+    let store: Arc<dyn TokenStore> = if let Some(pool) = &pool {
+        Arc::new(PgStore::new(pool.clone(), config.schema_layout))
+    } else {
+        warn!("Using the in-memory token store: tokens are lost on restart and not shared between replicas");
+
+        Arc::new(MemoryStore::new(expiration))
+    };
+
+    let lifecycle = Arc::new(Lifecycle::new());
+
+    let port = config.port;
+    let drain_period = config.drain_period;
+    let tls = config.tls.clone();
+    let mtls = config.mtls.clone();
+    let cors = config.cors.clone();
+    let dev = config.dev;
+
+    let state = AppState {
+        pool: pool.clone(),
+        store: store.clone(),
+        config: Arc::new(config),
+        lifecycle: lifecycle.clone(),
+        vault_status: vault_status.clone(),
+        clock: Arc::new(SystemClock),
+        metrics: Arc::new(Metrics::new(vault_status)),
+        supervisor,
+    };
 
     let swagger = SwaggerUi::new("/ui/api-docs").url("/api-docs/openapi.json", ApiDoc::openapi());
 
-    let cors = CorsLayer::new()
-        // allow `GET` and `POST` when accessing the resource
-        .allow_methods([Method::GET, Method::POST])
-        // allow requests from any origin
-        .allow_origin(Any);
+    let verify = Router::new()
+        .route("/verify", post(handlers::verify))
+        .layer(cors.verify());
+
+    let admin = Router::new()
+        .route("/admin/stats/clients", get(handlers::analytics::clients))
+        .route(
+            "/admin/stats/countries",
+            get(handlers::analytics::countries),
+        )
+        .route("/admin/stats/ips", get(handlers::analytics::ips))
+        .route("/admin/stats/verified", get(handlers::analytics::verified));
+
+    // with mTLS /verify and /admin are only served on its own listener, without
+    // it /admin is only served in dev mode
+    let (public, verify) = if mtls.is_some() {
+        (Router::new(), Some(verify.merge(admin)))
+    } else if dev {
+        warn!("Dev mode: /admin is served without mTLS");
+
+        (verify.merge(admin), None)
+    } else {
+        (verify, None)
+    };
 
     let app = Router::new()
         .route("/headers", get(handlers::headers))
-        .route("/token", get(handlers::token))
-        .route("/verify", post(handlers::verify))
-        .layer(
-            ServiceBuilder::new()
-                .layer(SetRequestHeaderLayer::if_not_present(
-                    HeaderName::from_static("x-request-id"),
-                    |_req: &_| HeaderValue::from_str(Ulid::new().to_string().as_str()).ok(),
-                ))
-                .layer(PropagateRequestIdLayer::new(HeaderName::from_static(
-                    "x-request-id",
-                )))
-                .layer(TraceLayer::new_for_http().make_span_with(make_span))
-                .layer(cors)
-                .layer(Extension(pool.clone())),
+        .layer(cors.global())
+        .merge(
+            Router::new()
+                .route("/token", get(handlers::token))
+                .layer(cors.token())
+                .layer(middleware::from_fn_with_state(store, cors::lookup_client)),
         )
+        .merge(public);
+
+    let probes = Router::new()
         .route("/health", get(handlers::health).options(handlers::health))
-        .layer(Extension(pool))
+        .route("/livez", get(handlers::livez))
+        .route("/readyz", get(handlers::readyz))
+        .route("/startupz", get(handlers::startupz));
+
+    let app = with_middleware(versioned(app), &state)
+        .merge(versioned(probes))
+        .with_state(state.clone())
         .merge(swagger);
 
-    let listener = TcpListener::bind(format!("::0:{port}")).await?;
+    // Load the certificates before listening so a broken one fails the startup
+    let tls_config = tls
+        .as_ref()
+        .map(|tls| tls::rustls_config(tls.clone(), None))
+        .transpose()?;
+
+    let listener = std::net::TcpListener::bind(format!("[::]:{port}"))?;
+    listener.set_nonblocking(true)?;
 
     info!("Listening on [::]:{}", port);
 
-    axum::serve(listener, app.into_make_service())
-        .with_graceful_shutdown(async move {
-            rx.recv().await;
-            info!("Gracefully shutdown");
-        })
-        .await?;
+    let verify_server = match (tls, mtls, verify) {
+        (Some(tls), Some(mtls), Some(verify)) => {
+            let config = tls::rustls_config(tls, Some(mtls.client_ca))?;
+
+            let listener = std::net::TcpListener::bind(format!("[::]:{}", mtls.port))?;
+            listener.set_nonblocking(true)?;
+
+            info!("Listening on [::]:{} (mTLS)", mtls.port);
+
+            Some((
+                axum_server::from_tcp(listener).acceptor(MtlsAcceptor::new(config, mtls.allowed)),
+                with_middleware(versioned(verify), &state).with_state(state),
+            ))
+        }
+        _ => None,
+    };
+
+    lifecycle.mark_started();
+
+    let handle = Handle::new();
+    let verify_handle = Handle::new();
+
+    tokio::spawn({
+        let handles = [handle.clone(), verify_handle.clone()];
+        let shutdown = shutdown::graceful(rx, lifecycle.clone(), Duration::from_secs(drain_period));
+
+        async move {
+            shutdown.await;
+
+            for handle in handles {
+                handle.graceful_shutdown(None);
+            }
+        }
+    });
+
+    let public_server = async {
+        match tls_config {
+            Some(config) => {
+                axum_server::from_tcp_rustls(listener, config)
+                    .handle(handle)
+                    .serve(app.into_make_service())
+                    .await
+            }
+            None => {
+                axum_server::from_tcp(listener)
+                    .handle(handle)
+                    .serve(app.into_make_service())
+                    .await
+            }
+        }
+        .context("Server failed")
+    };
+
+    let verify_server = async {
+        match verify_server {
+            Some((server, verify)) => server
+                .handle(verify_handle)
+                .serve(verify.into_make_service())
+                .await
+                .context("mTLS server failed"),
+            None => Ok(()),
+        }
+    };
+
+    tokio::try_join!(public_server, verify_server)?;
+
+    // In-flight requests are done, release the connections and credentials
+    if let Some(pool) = pool {
+        pool.close().await;
+    }
+
+    if !globals.dev {
+        // the lease of the credentials in use, not the initial one
+        let globals = GlobalArgs {
+            vault_db_lease_id: db_lease.borrow().clone(),
+            ..globals.clone()
+        };
+
+        vault::revoke::cleanup(&globals).await;
+    }
 
     Ok(())
 }
 
+// request id and tracing
+fn with_middleware(router: Router<AppState>, state: &AppState) -> Router<AppState> {
+    let make_span = {
+        let config = state.config.clone();
+        move |request: &Request<Body>| make_span(request, &config.log)
+    };
+
+    let trace = TraceLayer::new_for_http().make_span_with(make_span);
+
+    router.layer(
+        ServiceBuilder::new()
+            .layer(SetRequestHeaderLayer::if_not_present(
+                HeaderName::from_static("x-request-id"),
+                |_req: &_| HeaderValue::from_str(Ulid::new().to_string().as_str()).ok(),
+            ))
+            .layer(PropagateRequestIdLayer::new(HeaderName::from_static(
+                "x-request-id",
+            )))
+            .layer(trace)
+            .layer(middleware::from_fn_with_state(
+                state.metrics.clone(),
+                metrics::track,
+            ))
+            .layer(middleware::from_fn(error::request_id)),
+    )
+}
+
 // span
-fn make_span(request: &Request<Body>) -> Span {
-    let headers = request.headers();
+fn make_span(request: &Request<Body>, log: &logs::LogConfig) -> Span {
     let path = request.uri().path();
-    let request_id = headers
+
+    // always created for the trace, only the logs are sampled
+    let sampled = log.sampled(unversioned(path));
+
+    let request_id = request
+        .headers()
         .get("x-request-id")
         .and_then(|val| val.to_str().ok())
         .unwrap_or("none");
 
-    debug_span!("http-request", path, ?headers, request_id)
+    // set on the mTLS listener once the client certificate is verified
+    let client = request
+        .extensions()
+        .get::<ClientIdentity>()
+        .map_or_else(|| "none".to_string(), ToString::to_string);
+
+    // cookies and credentials are redacted
+    let headers = log.headers(request.headers());
+
+    let span = debug_span!(
+        "http-request",
+        path,
+        ?headers,
+        request_id,
+        client,
+        log.sampled = sampled
+    );
+
+    // continue the trace of the caller (traceparent/tracestate)
+    span.set_parent(propagation::extract(request.headers()));
+
+    span
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::clock::FixedClock;
+    use axum::http::StatusCode;
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_openapi_committed() {
+        assert!(
+            openapi().unwrap() == include_str!("../../openapi.json"),
+            "openapi.json is outdated, run: cargo run -- openapi > openapi.json"
+        );
+    }
+
+    #[test]
+    fn test_unversioned() {
+        assert_eq!(unversioned("/v1/token"), "/token");
+        assert_eq!(unversioned("/token"), "/token");
+        assert_eq!(unversioned("/v1"), "/v1");
+        assert_eq!(unversioned("/v10/token"), "/v10/token");
+    }
+
+    #[tokio::test]
+    async fn test_versioned() {
+        let app = versioned(Router::new().route("/livez", get(handlers::livez)))
+            .with_state(state::tests::state(FixedClock(Utc::now())));
+
+        for (path, status) in [
+            ("/v1/livez", StatusCode::OK),
+            ("/livez", StatusCode::OK),
+            ("/v1/v1/livez", StatusCode::NOT_FOUND),
+        ] {
+            let request = Request::builder().uri(path).body(Body::empty()).unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), status, "{path}");
+        }
+    }
 }
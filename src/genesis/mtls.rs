@@ -0,0 +1,210 @@
+use anyhow::{anyhow, Result};
+use axum::Extension;
+use axum_server::{
+    accept::{Accept, DefaultAcceptor},
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt, future::Future, io, path::PathBuf, pin::Pin, sync::Arc};
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tower::Layer;
+use tracing::{debug, warn};
+use x509_parser::{extensions::GeneralName, prelude::*};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MtlsConfig {
+    pub port: u16,
+    // CA bundle (PEM) used to verify the client certificates
+    pub client_ca: PathBuf,
+    // allowed subjects (CN or DN) or SPIFFE IDs, empty allows any client signed by the CA
+    #[serde(default)]
+    pub allowed: Vec<String>,
+}
+
+/// Identity of a caller authenticated with a client certificate, available
+/// as a request extension on the mTLS listener
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    pub subject: String,
+    pub common_name: Option<String>,
+    pub spiffe_id: Option<String>,
+}
+
+impl ClientIdentity {
+    /// Parse the identity from a DER encoded certificate
+    /// # Errors
+    /// Will return an error if the certificate can't be parsed
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        let (_, cert) = X509Certificate::from_der(der)
+            .map_err(|e| anyhow!("Invalid client certificate: {}", e))?;
+
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(ToString::to_string);
+
+        let spiffe_id = cert
+            .subject_alternative_name()
+            .ok()
+            .flatten()
+            .and_then(|san| {
+                san.value.general_names.iter().find_map(|name| match name {
+                    GeneralName::URI(uri) if uri.starts_with("spiffe://") => {
+                        Some((*uri).to_string())
+                    }
+                    _ => None,
+                })
+            });
+
+        Ok(Self {
+            subject: cert.subject().to_string(),
+            common_name,
+            spiffe_id,
+        })
+    }
+
+    #[must_use]
+    pub fn is_allowed(&self, allowed: &[String]) -> bool {
+        allowed.is_empty()
+            || allowed.iter().any(|entry| {
+                Some(entry) == self.spiffe_id.as_ref()
+                    || Some(entry) == self.common_name.as_ref()
+                    || *entry == self.subject
+            })
+    }
+}
+
+impl fmt::Display for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.spiffe_id, &self.common_name) {
+            (Some(spiffe_id), _) => write!(f, "{spiffe_id}"),
+            (None, Some(common_name)) => write!(f, "{common_name}"),
+            (None, None) => write!(f, "{}", self.subject),
+        }
+    }
+}
+
+/// TLS acceptor that requires a client certificate from an allowed identity
+/// and adds its `ClientIdentity` to every request of the connection
+#[derive(Clone)]
+pub struct MtlsAcceptor {
+    inner: RustlsAcceptor<DefaultAcceptor>,
+    allowed: Arc<Vec<String>>,
+}
+
+impl MtlsAcceptor {
+    #[must_use]
+    pub fn new(config: RustlsConfig, allowed: Vec<String>) -> Self {
+        Self {
+            inner: RustlsAcceptor::new(config),
+            allowed: Arc::new(allowed),
+        }
+    }
+}
+
+type AcceptFuture<S> = Pin<
+    Box<
+        dyn Future<
+                Output = io::Result<(
+                    TlsStream<TcpStream>,
+                    axum::middleware::AddExtension<S, ClientIdentity>,
+                )>,
+            > + Send,
+    >,
+>;
+
+impl<S> Accept<TcpStream, S> for MtlsAcceptor
+where
+    S: Send + 'static,
+{
+    type Stream = TlsStream<TcpStream>;
+    type Service = axum::middleware::AddExtension<S, ClientIdentity>;
+    type Future = AcceptFuture<S>;
+
+    fn accept(&self, stream: TcpStream, service: S) -> Self::Future {
+        let inner = self.inner.clone();
+        let allowed = self.allowed.clone();
+
+        Box::pin(async move {
+            let (stream, service) = inner.accept(stream, service).await?;
+
+            // the client verifier already rejected connections without a valid certificate
+            let identity = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .ok_or_else(|| anyhow!("Missing client certificate"))
+                .and_then(|cert| ClientIdentity::from_der(cert))
+                .map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e.to_string()))?;
+
+            if !identity.is_allowed(&allowed) {
+                warn!("Client certificate not allowed: {}", identity.subject);
+
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "client certificate not allowed",
+                ));
+            }
+
+            debug!("Client certificate: {}", identity);
+
+            Ok((stream, Extension(identity).layer(service)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair, SanType};
+
+    fn certificate(cn: &str, spiffe_id: Option<&str>) -> Vec<u8> {
+        let mut params = CertificateParams::default();
+        let mut dn = DistinguishedName::new();
+        dn.push(DnType::CommonName, cn);
+        params.distinguished_name = dn;
+
+        if let Some(spiffe_id) = spiffe_id {
+            params.subject_alt_names = vec![SanType::URI(spiffe_id.try_into().unwrap())];
+        }
+
+        let key = KeyPair::generate().unwrap();
+
+        params.self_signed(&key).unwrap().der().to_vec()
+    }
+
+    #[test]
+    fn test_client_identity() {
+        let identity = ClientIdentity::from_der(&certificate(
+            "permesi",
+            Some("spiffe://permesi.dev/ns/default/sa/permesi"),
+        ))
+        .unwrap();
+
+        assert_eq!(identity.common_name.as_deref(), Some("permesi"));
+        assert_eq!(
+            identity.spiffe_id.as_deref(),
+            Some("spiffe://permesi.dev/ns/default/sa/permesi")
+        );
+        assert_eq!(
+            identity.to_string(),
+            "spiffe://permesi.dev/ns/default/sa/permesi"
+        );
+    }
+
+    #[test]
+    fn test_client_identity_allowed() {
+        let identity = ClientIdentity::from_der(&certificate("permesi", None)).unwrap();
+
+        assert!(identity.is_allowed(&[]));
+        assert!(identity.is_allowed(&["permesi".to_string()]));
+        assert!(identity.is_allowed(&["CN=permesi".to_string()]));
+        assert!(!identity.is_allowed(&["frontend".to_string()]));
+        assert!(!identity.is_allowed(&["spiffe://permesi.dev/ns/default/sa/permesi".to_string()]));
+    }
+}
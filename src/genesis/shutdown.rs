@@ -0,0 +1,65 @@
+use crate::genesis::lifecycle::Lifecycle;
+use std::sync::Arc;
+use tokio::{
+    signal,
+    sync::mpsc,
+    time::{sleep, Duration},
+};
+use tracing::{error, info, warn};
+
+/// Wait for SIGINT, SIGTERM or a shutdown requested by a background task, e.g.
+/// a failed Vault renewal
+async fn wait(rx: &mut mpsc::UnboundedReceiver<&'static str>) {
+    let ctrl_c = async {
+        if let Err(e) = signal::ctrl_c().await {
+            error!("Failed to listen for SIGINT: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => info!("Received SIGINT"),
+        () = terminate => info!("Received SIGTERM"),
+        Some(reason) = rx.recv() => warn!("{}", reason),
+    }
+}
+
+/// Graceful shutdown future for `axum::serve`
+///
+/// Once a signal is received the server is marked as draining so that the
+/// readiness check fails, then waits `drain_period` before axum stops
+/// accepting new connections and waits for the in-flight requests.
+pub async fn graceful(
+    mut rx: mpsc::UnboundedReceiver<&'static str>,
+    lifecycle: Arc<Lifecycle>,
+    drain_period: Duration,
+) {
+    wait(&mut rx).await;
+
+    lifecycle.start_draining();
+
+    info!(
+        "Draining, waiting {} seconds before shutting down",
+        drain_period.as_secs()
+    );
+
+    sleep(drain_period).await;
+
+    info!("Gracefully shutdown");
+}
@@ -0,0 +1,76 @@
+use crate::{
+    cli::config::Config,
+    genesis::{
+        clock::Clock,
+        error::ApiError,
+        lifecycle::Lifecycle,
+        metrics::Metrics,
+        store::{postgres, TokenStore},
+        supervisor::Supervisor,
+    },
+    vault::status::Status,
+};
+use sqlx::PgPool;
+use std::sync::Arc;
+use tracing::error;
+
+/// Shared state of the handlers, cheap to clone
+#[derive(Debug, Clone)]
+pub struct AppState {
+    // None with the in-memory store
+    pub pool: Option<PgPool>,
+    pub store: Arc<dyn TokenStore>,
+    pub config: Arc<Config>,
+    pub lifecycle: Arc<Lifecycle>,
+    pub vault_status: Arc<Status>,
+    pub clock: Arc<dyn Clock>,
+    pub metrics: Arc<Metrics>,
+    pub supervisor: Supervisor,
+}
+
+impl AppState {
+    /// Error for a failed store or database query, when the credentials were
+    /// rejected the supervisor is told and the client gets a 503
+    #[must_use]
+    pub fn store_error(&self, err: anyhow::Error) -> ApiError {
+        if postgres::insufficient_privilege(&err) {
+            error!("Database credentials are invalid: {:#}", err);
+
+            self.supervisor.credentials_invalid();
+
+            ApiError::Unavailable
+        } else {
+            ApiError::Internal(err)
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::{
+        genesis::{clock::Clock, store::MemoryStore},
+        vault::status::Status,
+    };
+    use std::time::Duration;
+
+    /// State with an in-memory store, no database needed
+    pub fn state(clock: impl Clock + 'static) -> AppState {
+        with_store(clock, Arc::new(MemoryStore::new(Duration::from_secs(120))))
+    }
+
+    pub fn with_store(clock: impl Clock + 'static, store: Arc<dyn TokenStore>) -> AppState {
+        let vault_status = Arc::new(Status::disabled());
+
+        AppState {
+            pool: None,
+            store,
+            config: Arc::new(Config::default()),
+            lifecycle: Arc::new(Lifecycle::new()),
+            vault_status: vault_status.clone(),
+            clock: Arc::new(clock),
+            metrics: Arc::new(Metrics::new(vault_status)),
+            supervisor: Supervisor::new().0,
+        }
+    }
+}
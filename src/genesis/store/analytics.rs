@@ -0,0 +1,374 @@
+use crate::genesis::store::postgres::Layout;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+
+/// Maximum number of buckets per query
+const MAX_BUCKETS: i64 = 10_000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    Minute,
+    #[default]
+    Hour,
+    Day,
+}
+
+impl Bucket {
+    // date_trunc field
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Minute => "minute",
+            Self::Hour => "hour",
+            Self::Day => "day",
+        }
+    }
+
+    const fn delta(self) -> TimeDelta {
+        match self {
+            Self::Minute => TimeDelta::minutes(1),
+            Self::Hour => TimeDelta::hours(1),
+            Self::Day => TimeDelta::days(1),
+        }
+    }
+}
+
+/// Time range, `from` inclusive and `to` exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+}
+
+impl Range {
+    /// # Errors
+    /// Will return an error if the range is empty or has too many buckets
+    pub fn validate(&self, bucket: Bucket) -> Result<()> {
+        if self.from >= self.to {
+            return Err(anyhow!("from must be before to"));
+        }
+
+        let buckets = (self.to - self.from).num_seconds() / bucket.delta().num_seconds();
+
+        if buckets > MAX_BUCKETS {
+            return Err(anyhow!(
+                "Too many {} buckets ({}), use a shorter range or a larger bucket",
+                bucket.as_str(),
+                buckets
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Tokens issued and verified per client in a bucket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientUsage {
+    pub bucket: DateTime<Utc>,
+    pub client: String,
+    pub issued: i64,
+    // None when the verifications are not tracked
+    pub verified: Option<i64>,
+}
+
+/// Tokens per country or IP address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Top {
+    pub key: String,
+    pub tokens: i64,
+}
+
+/// Tokens issued and verified in the range
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Verified {
+    pub issued: i64,
+    pub verified: i64,
+}
+
+impl Verified {
+    /// Share of the issued tokens that were verified, 0 to 1
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn share(&self) -> f64 {
+        if self.issued == 0 {
+            return 0.0;
+        }
+
+        self.verified as f64 / self.issued as f64
+    }
+}
+
+// issue time, range predicate ($1, $2) and metadata join for the layout
+const fn sql(layout: Layout) -> (&'static str, &'static str, &'static str) {
+    match layout {
+        // ULIDs are ordered by time, the range is a primary key scan
+        Layout::Ulid => (
+            "(t.id::timestamp AT TIME ZONE 'UTC')",
+            "t.id >= ($1 AT TIME ZONE 'UTC')::ulid AND t.id < ($2 AT TIME ZONE 'UTC')::ulid",
+            "m.id = t.id",
+        ),
+        Layout::Portable => (
+            "t.issued_at",
+            "t.issued_at >= $1 AND t.issued_at < $2",
+            "m.id = t.id",
+        ),
+        Layout::Partitioned => (
+            "t.issued_at",
+            "t.issued_at >= $1 AND t.issued_at < $2",
+            "m.id = t.id AND m.issued_at = t.issued_at",
+        ),
+    }
+}
+
+/// Tokens issued and verified per client and bucket, `verified_at` is only
+/// read when `track_verified`, the column may not exist otherwise
+/// # Errors
+/// Will return an error if the query fails
+pub async fn clients(
+    pool: &PgPool,
+    layout: Layout,
+    range: Range,
+    bucket: Bucket,
+    track_verified: bool,
+) -> Result<Vec<ClientUsage>> {
+    let (issued_at, range_sql, _) = sql(layout);
+
+    let verified = if track_verified {
+        "count(t.verified_at)"
+    } else {
+        "NULL::bigint"
+    };
+
+    let query = format!(
+        "SELECT date_trunc($3, {issued_at} AT TIME ZONE 'UTC') AT TIME ZONE 'UTC' AS bucket, c.name AS client, \
+         count(*) AS issued, {verified} AS verified \
+         FROM tokens t JOIN clients c ON c.id = t.client_id WHERE {range_sql} GROUP BY 1, 2 ORDER BY 1, 2"
+    );
+
+    sqlx::query(&query)
+        .bind(range.from)
+        .bind(range.to)
+        .bind(bucket.as_str())
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| {
+            Ok(ClientUsage {
+                bucket: row.try_get("bucket")?,
+                client: row.try_get("client")?,
+                issued: row.try_get("issued")?,
+                verified: row.try_get("verified")?,
+            })
+        })
+        .collect()
+}
+
+/// Countries with the most tokens
+/// # Errors
+/// Will return an error if the query fails
+pub async fn countries(
+    pool: &PgPool,
+    layout: Layout,
+    range: Range,
+    limit: i64,
+) -> Result<Vec<Top>> {
+    top(pool, layout, range, limit, "m.country::text").await
+}
+
+/// IP addresses with the most tokens
+/// # Errors
+/// Will return an error if the query fails
+pub async fn ips(pool: &PgPool, layout: Layout, range: Range, limit: i64) -> Result<Vec<Top>> {
+    top(pool, layout, range, limit, "host(m.ip_address)").await
+}
+
+async fn top(
+    pool: &PgPool,
+    layout: Layout,
+    range: Range,
+    limit: i64,
+    key: &str,
+) -> Result<Vec<Top>> {
+    let (_, range_sql, join) = sql(layout);
+
+    let query = format!(
+        "SELECT {key} AS key, count(*) AS tokens FROM tokens t JOIN metadata m ON {join} \
+         WHERE {range_sql} AND {key} IS NOT NULL GROUP BY 1 ORDER BY 2 DESC, 1 LIMIT $3"
+    );
+
+    sqlx::query(&query)
+        .bind(range.from)
+        .bind(range.to)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| {
+            Ok(Top {
+                key: row.try_get("key")?,
+                tokens: row.try_get("tokens")?,
+            })
+        })
+        .collect()
+}
+
+/// Tokens issued and verified in the range
+/// # Errors
+/// Will return an error if the query fails
+pub async fn verified(pool: &PgPool, layout: Layout, range: Range) -> Result<Verified> {
+    let (_, range_sql, _) = sql(layout);
+
+    let row = sqlx::query(&format!(
+        "SELECT count(*) AS issued, count(t.verified_at) AS verified FROM tokens t WHERE {range_sql}"
+    ))
+    .bind(range.from)
+    .bind(range.to)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(Verified {
+        issued: row.try_get("issued")?,
+        verified: row.try_get("verified")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::store::{
+        partitions::{self, PartitionsConfig},
+        Metadata, PgStore, TokenStore,
+    };
+    use chrono::TimeZone;
+    use std::time::SystemTime;
+    use ulid::Ulid;
+
+    #[test]
+    fn test_range() {
+        let from = Utc.with_ymd_and_hms(2024, 2, 23, 0, 0, 0).unwrap();
+
+        let range = Range {
+            from,
+            to: from + TimeDelta::days(1),
+        };
+        assert!(range.validate(Bucket::Minute).is_ok());
+        assert!(range.validate(Bucket::Day).is_ok());
+
+        let range = Range {
+            from,
+            to: from + TimeDelta::days(30),
+        };
+        assert!(range.validate(Bucket::Minute).is_err());
+        assert!(range.validate(Bucket::Hour).is_ok());
+
+        let range = Range { from, to: from };
+        assert!(range.validate(Bucket::Hour).is_err());
+    }
+
+    #[test]
+    fn test_share() {
+        assert!((Verified::default().share()).abs() < f64::EPSILON);
+
+        let verified = Verified {
+            issued: 4,
+            verified: 1,
+        };
+        assert!((verified.share() - 0.25).abs() < f64::EPSILON);
+    }
+
+    // the queries of GENESIS_TEST_SCHEMA_LAYOUT (ulid by default) against the
+    // database of GENESIS_TEST_DSN, skipped without it
+    #[tokio::test]
+    async fn test_queries() {
+        let Ok(dsn) = std::env::var("GENESIS_TEST_DSN") else {
+            return;
+        };
+
+        let layout: Layout = std::env::var("GENESIS_TEST_SCHEMA_LAYOUT")
+            .map_or(Ok(Layout::Ulid), |layout| {
+                serde_json::from_value(serde_json::Value::String(layout))
+            })
+            .unwrap();
+
+        let pool = PgPool::connect(&dsn).await.unwrap();
+        let store = PgStore::new(pool.clone(), layout);
+
+        // the partitions of the current tokens, as created on startup
+        if layout == Layout::Partitioned {
+            partitions::maintain(&pool, &PartitionsConfig::default(), false, Utc::now())
+                .await
+                .unwrap();
+        }
+
+        let name = format!("analytics-{}", Ulid::new());
+        let client_id: i16 =
+            sqlx::query_scalar("INSERT INTO clients (name) VALUES ($1) RETURNING id")
+                .bind(&name)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+
+        // seconds apart so the range holds both
+        let now = Utc::now();
+        let metadata = Metadata {
+            ip_address: "198.51.100.7".parse().ok(),
+            country: Some("CH".to_string()),
+            user_agent: None,
+        };
+        let tokens: Vec<Ulid> = (0..2)
+            .map(|i| Ulid::from_datetime(SystemTime::from(now - TimeDelta::seconds(i))))
+            .collect();
+        for token in &tokens {
+            store.insert(token, client_id, &metadata).await.unwrap();
+        }
+        store.record_verified(&tokens[0]).await.unwrap();
+
+        let range = Range {
+            from: now - TimeDelta::minutes(1),
+            to: now + TimeDelta::minutes(1),
+        };
+
+        let usage: Vec<ClientUsage> = clients(&pool, layout, range, Bucket::Hour, true)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|usage| usage.client == name)
+            .collect();
+        assert_eq!(usage.iter().map(|usage| usage.issued).sum::<i64>(), 2);
+        assert_eq!(
+            usage.iter().filter_map(|usage| usage.verified).sum::<i64>(),
+            1
+        );
+
+        let untracked = clients(&pool, layout, range, Bucket::Minute, false)
+            .await
+            .unwrap();
+        assert!(untracked.iter().all(|usage| usage.verified.is_none()));
+
+        let ips = ips(&pool, layout, range, 100).await.unwrap();
+        assert!(ips
+            .iter()
+            .any(|top| top.key == "198.51.100.7" && top.tokens >= 2));
+
+        let countries = countries(&pool, layout, range, 100).await.unwrap();
+        assert!(countries
+            .iter()
+            .any(|top| top.key == "CH" && top.tokens >= 2));
+
+        let verified = verified(&pool, layout, range).await.unwrap();
+        assert!(verified.issued >= 2);
+        assert!(verified.verified >= 1);
+
+        for token in &tokens {
+            store.revoke(token).await.unwrap();
+        }
+        sqlx::query("DELETE FROM clients WHERE id = $1")
+            .bind(client_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
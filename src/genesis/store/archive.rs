@@ -0,0 +1,516 @@
+use crate::genesis::store::postgres::Layout;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use flate2::{write::GzEncoder, Compression};
+use permesi_genesis_client::TOKEN_EXPIRATION;
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgRow, PgPool, Postgres, Row, Transaction};
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{task, time::sleep};
+use tracing::{error, info};
+use ulid::Ulid;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArchiveConfig {
+    // directory for the archive files
+    pub dir: PathBuf,
+    // seconds between runs
+    pub interval: u64,
+    // rows per file, more rows are written to the next file
+    pub max_rows: u32,
+    // seconds tokens are kept in the database before they are archived
+    pub retention: u64,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::new(),
+            interval: 60,
+            max_rows: 100_000,
+            retention: 86_400,
+        }
+    }
+}
+
+impl ArchiveConfig {
+    /// # Errors
+    /// Will return an error if the directory is missing, nothing would be
+    /// archived or valid tokens would be archived
+    pub fn validate(&self) -> Result<()> {
+        if self.dir.as_os_str().is_empty() {
+            return Err(anyhow!("archive dir is required"));
+        }
+
+        if self.interval == 0 || self.max_rows == 0 {
+            return Err(anyhow!("archive interval and max_rows must be at least 1"));
+        }
+
+        if self.retention < TOKEN_EXPIRATION.unsigned_abs() {
+            return Err(anyhow!(
+                "archive retention must be at least the token expiration ({TOKEN_EXPIRATION} seconds)"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Expired token with its metadata, one line of the archive
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    pub id: String,
+    pub issued_at: DateTime<Utc>,
+    pub client: String,
+    pub ip_address: Option<String>,
+    pub country: Option<String>,
+    pub user_agent: Option<String>,
+    // null unless --track-verified
+    pub verified_at: Option<DateTime<Utc>>,
+}
+
+// rows past the retention, locked until they are deleted, `verified_at` is
+// only read when `track_verified`, the column may not exist otherwise
+fn select(layout: Layout, track_verified: bool) -> String {
+    let verified_at = if track_verified {
+        "t.verified_at"
+    } else {
+        "NULL::timestamptz"
+    };
+
+    match layout {
+        Layout::Ulid => format!(
+            "SELECT t.id::text AS id, t.id::timestamp AT TIME ZONE 'UTC' AS issued_at, c.name AS client, host(m.ip_address) AS ip_address, m.country::text AS country, m.user_agent, {verified_at} AS verified_at \
+             FROM tokens t JOIN clients c ON c.id = t.client_id LEFT JOIN metadata m ON m.id = t.id \
+             WHERE t.id::timestamp < $1 AT TIME ZONE 'UTC' ORDER BY t.id LIMIT $2 FOR UPDATE OF t SKIP LOCKED"
+        ),
+        Layout::Portable => format!(
+            "SELECT t.id, t.issued_at, c.name AS client, host(m.ip_address) AS ip_address, m.country::text AS country, m.user_agent, {verified_at} AS verified_at \
+             FROM tokens t JOIN clients c ON c.id = t.client_id LEFT JOIN metadata m ON m.id = t.id \
+             WHERE t.issued_at < $1 ORDER BY t.issued_at LIMIT $2 FOR UPDATE OF t SKIP LOCKED"
+        ),
+        Layout::Partitioned => format!(
+            "SELECT t.id, t.issued_at, c.name AS client, host(m.ip_address) AS ip_address, m.country::text AS country, m.user_agent, {verified_at} AS verified_at \
+             FROM tokens t JOIN clients c ON c.id = t.client_id LEFT JOIN metadata m ON m.id = t.id AND m.issued_at = t.issued_at \
+             WHERE t.issued_at < $1 ORDER BY t.issued_at LIMIT $2 FOR UPDATE OF t SKIP LOCKED"
+        ),
+    }
+}
+
+fn record(layout: Layout, row: &PgRow) -> Result<Record> {
+    // the portable layouts store the ULID as uuid
+    let id = match layout {
+        Layout::Ulid => row.try_get("id")?,
+        Layout::Portable | Layout::Partitioned => {
+            Ulid::from(row.try_get::<Uuid, _>("id")?.as_u128()).to_string()
+        }
+    };
+
+    Ok(Record {
+        id,
+        issued_at: row.try_get("issued_at")?,
+        client: row.try_get("client")?,
+        ip_address: row.try_get("ip_address")?,
+        country: row
+            .try_get::<Option<String>, _>("country")?
+            .map(|country| country.trim().to_string()),
+        user_agent: row.try_get("user_agent")?,
+        verified_at: row.try_get("verified_at")?,
+    })
+}
+
+/// Archive file written to a hidden temporary file, published by renaming it
+#[derive(Debug)]
+pub struct Pending {
+    tmp: PathBuf,
+    path: PathBuf,
+}
+
+impl Pending {
+    /// Rename the temporary file so readers see it
+    /// # Errors
+    /// Will return an error if the file can't be renamed, it is kept
+    pub fn publish(self) -> Result<PathBuf> {
+        fs::rename(&self.tmp, &self.path).with_context(|| {
+            format!(
+                "Failed to rename {} to {}, the archived rows are in the former",
+                self.tmp.display(),
+                self.path.display()
+            )
+        })?;
+
+        Ok(self.path)
+    }
+
+    /// Remove the temporary file, the rows are archived again on the next run
+    pub fn discard(self) {
+        let _ = fs::remove_file(&self.tmp);
+    }
+}
+
+/// Archive the tokens issued before `cutoff` and delete them
+///
+/// The file is only published once the delete is committed, a failed run
+/// leaves neither a file nor missing rows and no row is archived twice
+/// # Errors
+/// Will return an error if the rows can't be read, written or deleted
+pub async fn archive(
+    pool: &PgPool,
+    layout: Layout,
+    config: &ArchiveConfig,
+    track_verified: bool,
+    cutoff: DateTime<Utc>,
+) -> Result<usize> {
+    let mut tx = pool.begin().await?;
+
+    let records = sqlx::query(&select(layout, track_verified))
+        .bind(cutoff)
+        .bind(i64::from(config.max_rows))
+        .fetch_all(&mut *tx)
+        .await
+        .context("Failed to read the tokens to archive")?
+        .iter()
+        .map(|row| record(layout, row))
+        .collect::<Result<Vec<_>>>()?;
+
+    if records.is_empty() {
+        return Ok(0);
+    }
+
+    let pending = task::spawn_blocking({
+        let dir = config.dir.clone();
+        let records = records.clone();
+        move || write(&dir, &records)
+    })
+    .await??;
+
+    if let Err(err) = delete(&mut tx, layout, &records, cutoff).await {
+        pending.discard();
+        return Err(err);
+    }
+
+    if let Err(err) = tx.commit().await {
+        pending.discard();
+        return Err(anyhow!(err).context("Failed to delete the archived tokens"));
+    }
+
+    let path = task::spawn_blocking(move || pending.publish()).await??;
+
+    info!("Archived {} tokens to {}", records.len(), path.display());
+
+    Ok(records.len())
+}
+
+async fn delete(
+    tx: &mut Transaction<'_, Postgres>,
+    layout: Layout,
+    records: &[Record],
+    cutoff: DateTime<Utc>,
+) -> Result<()> {
+    let ids: Vec<String> = records.iter().map(|record| record.id.clone()).collect();
+
+    let delete = match layout {
+        Layout::Ulid => {
+            sqlx::query("DELETE FROM tokens WHERE id = ANY(SELECT unnest($1::text[])::ulid)")
+                .bind(ids)
+        }
+        Layout::Portable | Layout::Partitioned => {
+            let ids = ids
+                .iter()
+                .map(|id| Ulid::from_string(id).map(|ulid| Uuid::from_u128(ulid.0)))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            // the cutoff prunes the partitions
+            sqlx::query("DELETE FROM tokens WHERE id = ANY($1) AND issued_at < $2")
+                .bind(ids)
+                .bind(cutoff)
+        }
+    };
+
+    delete
+        .execute(&mut **tx)
+        .await
+        .context("Failed to delete the archived tokens")?;
+
+    Ok(())
+}
+
+/// Write the records to a hidden gzip NDJSON file, readers only see it once
+/// it is published
+/// # Errors
+/// Will return an error if the file can't be written
+pub fn write(dir: &Path, records: &[Record]) -> Result<Pending> {
+    let (Some(first), Some(last)) = (records.first(), records.last()) else {
+        return Err(anyhow!("No records to archive"));
+    };
+
+    let name = format!(
+        "tokens-{}-{}.ndjson.gz",
+        first.issued_at.format("%Y%m%dT%H%M%SZ"),
+        last.id
+    );
+
+    let path = dir.join(&name);
+    let tmp = dir.join(format!(".{name}.tmp"));
+
+    let result = (|| -> Result<()> {
+        let mut encoder =
+            GzEncoder::new(BufWriter::new(File::create(&tmp)?), Compression::default());
+
+        for record in records {
+            serde_json::to_writer(&mut encoder, record)?;
+            encoder.write_all(b"\n")?;
+        }
+
+        let file = encoder
+            .finish()?
+            .into_inner()
+            .map_err(|err| anyhow!("Failed to flush {}: {}", tmp.display(), err))?;
+
+        file.sync_all()?;
+
+        Ok(())
+    })();
+
+    if let Err(err) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(err.context(format!("Failed to write {}", tmp.display())));
+    }
+
+    Ok(Pending { tmp, path })
+}
+
+/// Archive the tokens older than the retention periodically
+pub fn spawn(pool: PgPool, layout: Layout, config: ArchiveConfig, track_verified: bool) {
+    tokio::spawn(async move {
+        loop {
+            sleep(Duration::from_secs(config.interval)).await;
+
+            let cutoff = Utc::now() - Duration::from_secs(config.retention);
+
+            // full files mean more rows are waiting
+            loop {
+                match archive(&pool, layout, &config, track_verified, cutoff).await {
+                    Ok(rows) if rows == config.max_rows as usize => continue,
+                    Ok(_) => break,
+                    Err(err) => {
+                        error!("Failed to archive tokens: {:#}", err);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::store::{
+        partitions::{self, PartitionsConfig},
+        Metadata, PgStore, TokenStore,
+    };
+    use chrono::{TimeDelta, TimeZone};
+    use flate2::read::GzDecoder;
+    use std::{
+        io::{BufRead, BufReader},
+        time::SystemTime,
+    };
+
+    fn read(path: &Path) -> Vec<Record> {
+        BufReader::new(GzDecoder::new(File::open(path).unwrap()))
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect()
+    }
+
+    fn records() -> Vec<Record> {
+        let issued_at = Utc.with_ymd_and_hms(2024, 2, 23, 10, 46, 47).unwrap();
+
+        vec![
+            Record {
+                id: "01HQAS6A6SGD3Z1V7VF86Q0B6P".to_string(),
+                issued_at,
+                client: "unknown".to_string(),
+                ip_address: Some("192.0.2.1".to_string()),
+                country: Some("CH".to_string()),
+                user_agent: Some("curl/8.6.0".to_string()),
+                verified_at: Some(issued_at),
+            },
+            Record {
+                id: "01HQAS6A6SV2A93NMKH0S03CD1".to_string(),
+                issued_at,
+                client: "permesi".to_string(),
+                ip_address: None,
+                country: None,
+                user_agent: None,
+                verified_at: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(ArchiveConfig::default().validate().is_err());
+
+        let config = ArchiveConfig {
+            dir: PathBuf::from("/var/lib/genesis/archive"),
+            ..ArchiveConfig::default()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = ArchiveConfig {
+            max_rows: 0,
+            ..config
+        };
+        assert!(config.validate().is_err());
+
+        // valid tokens are never archived
+        let config = ArchiveConfig {
+            max_rows: 1,
+            retention: 60,
+            ..config
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_select() {
+        for layout in [Layout::Ulid, Layout::Portable, Layout::Partitioned] {
+            assert!(select(layout, true).contains("t.verified_at AS verified_at"));
+            assert!(!select(layout, false).contains("t.verified_at"));
+        }
+    }
+
+    #[test]
+    fn test_write() {
+        let dir = std::env::temp_dir().join(format!("genesis-archive-{}", Ulid::new()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // hidden until published
+        let pending = write(&dir, &records()).unwrap();
+        assert!(!pending.path.exists());
+
+        let path = pending.publish().unwrap();
+        assert_eq!(
+            path.file_name().and_then(|name| name.to_str()),
+            Some("tokens-20240223T104647Z-01HQAS6A6SV2A93NMKH0S03CD1.ndjson.gz")
+        );
+
+        assert_eq!(read(&path), records());
+
+        // only the complete file is left
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        assert!(write(&dir, &[]).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_discard() {
+        let dir = std::env::temp_dir().join(format!("genesis-archive-{}", Ulid::new()));
+        fs::create_dir_all(&dir).unwrap();
+
+        write(&dir, &records()).unwrap().discard();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // archive against the database of GENESIS_TEST_DSN with the layout of
+    // GENESIS_TEST_SCHEMA_LAYOUT (ulid by default), skipped without it
+    #[tokio::test]
+    async fn test_archive() {
+        let Ok(dsn) = std::env::var("GENESIS_TEST_DSN") else {
+            return;
+        };
+
+        let layout: Layout = std::env::var("GENESIS_TEST_SCHEMA_LAYOUT")
+            .map_or(Ok(Layout::Ulid), |layout| {
+                serde_json::from_value(serde_json::Value::String(layout))
+            })
+            .unwrap();
+
+        let pool = PgPool::connect(&dsn).await.unwrap();
+        let store = PgStore::new(pool.clone(), layout);
+
+        // a day old, still within the default partition retention
+        let issued_at = Utc::now() - TimeDelta::hours(23);
+
+        if layout == Layout::Partitioned {
+            partitions::maintain(&pool, &PartitionsConfig::default(), false, issued_at)
+                .await
+                .unwrap();
+        }
+
+        let name = format!("archive-{}", Ulid::new());
+        let client_id: i16 =
+            sqlx::query_scalar("INSERT INTO clients (name) VALUES ($1) RETURNING id")
+                .bind(&name)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+
+        let tokens: Vec<Ulid> = (0..2)
+            .map(|i| Ulid::from_datetime(SystemTime::from(issued_at - TimeDelta::seconds(i))))
+            .collect();
+        for token in &tokens {
+            store
+                .insert(token, client_id, &Metadata::default())
+                .await
+                .unwrap();
+        }
+        store.record_verified(&tokens[0]).await.unwrap();
+
+        let dir = std::env::temp_dir().join(format!("genesis-archive-{}", Ulid::new()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let config = ArchiveConfig {
+            dir: dir.clone(),
+            ..ArchiveConfig::default()
+        };
+        let cutoff = issued_at + TimeDelta::hours(1);
+
+        assert!(archive(&pool, layout, &config, true, cutoff).await.unwrap() >= 2);
+
+        let archived: Vec<Record> = fs::read_dir(&dir)
+            .unwrap()
+            .flat_map(|entry| read(&entry.unwrap().path()))
+            .filter(|record| record.client == name)
+            .collect();
+        assert_eq!(archived.len(), 2);
+        assert_eq!(
+            archived
+                .iter()
+                .filter(|record| record.verified_at.is_some())
+                .count(),
+            1
+        );
+
+        for token in &tokens {
+            assert!(!store.verify(token).await.unwrap());
+        }
+
+        // the archived rows are gone, nothing is archived twice
+        assert_eq!(
+            archive(&pool, layout, &config, true, cutoff).await.unwrap(),
+            0
+        );
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+        sqlx::query("DELETE FROM clients WHERE id = $1")
+            .bind(client_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
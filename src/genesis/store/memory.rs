@@ -0,0 +1,249 @@
+use crate::genesis::store::{Client, Metadata, TokenStore};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::RwLock,
+    time::Duration,
+};
+use ulid::Ulid;
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    client_id: i16,
+    metadata: Metadata,
+    // first verification, recorded with --track-verified
+    verified_at: Option<DateTime<Utc>>,
+}
+
+/// Tokens kept in memory, for tests and single node dev mode, tokens are lost
+/// on restart and not shared between replicas
+#[derive(Debug)]
+pub struct MemoryStore {
+    // how long tokens are kept, expired tokens are pruned on insert
+    ttl: Duration,
+    // ordered by ULID, so by the time they were issued
+    tokens: RwLock<BTreeMap<Ulid, Entry>>,
+    clients: RwLock<HashMap<Uuid, Client>>,
+}
+
+impl MemoryStore {
+    #[must_use]
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            tokens: RwLock::new(BTreeMap::new()),
+            clients: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Register a client
+    /// # Errors
+    /// Will return an error if the lock is poisoned
+    pub fn add_client(&self, uuid: Uuid, client: Client) -> Result<()> {
+        self.clients
+            .write()
+            .map_err(|_| anyhow!("clients lock poisoned"))?
+            .insert(uuid, client);
+
+        Ok(())
+    }
+
+    /// Number of stored tokens
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.tokens.read().map_or(0, |tokens| tokens.len())
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// When the token was first verified
+    #[must_use]
+    pub fn verified_at(&self, token: &Ulid) -> Option<DateTime<Utc>> {
+        self.tokens.read().ok()?.get(token)?.verified_at
+    }
+
+    /// Metadata stored with the token
+    #[must_use]
+    pub fn metadata(&self, token: &Ulid) -> Option<(i16, Metadata)> {
+        self.tokens
+            .read()
+            .ok()?
+            .get(token)
+            .map(|entry| (entry.client_id, entry.metadata.clone()))
+    }
+}
+
+#[async_trait]
+impl TokenStore for MemoryStore {
+    async fn lookup_client(&self, uuid: Uuid) -> Result<Option<Client>> {
+        Ok(self
+            .clients
+            .read()
+            .map_err(|_| anyhow!("clients lock poisoned"))?
+            .get(&uuid)
+            .cloned())
+    }
+
+    async fn insert(&self, token: &Ulid, client_id: i16, metadata: &Metadata) -> Result<()> {
+        // drop the tokens issued before the TTL, they are sorted by time
+        let ttl = u64::try_from(self.ttl.as_millis()).unwrap_or(u64::MAX);
+        let cutoff = Ulid::from_parts(token.timestamp_ms().saturating_sub(ttl), 0);
+
+        let entry = Entry {
+            client_id,
+            metadata: metadata.clone(),
+            verified_at: None,
+        };
+
+        let mut tokens = self
+            .tokens
+            .write()
+            .map_err(|_| anyhow!("tokens lock poisoned"))?;
+
+        *tokens = tokens.split_off(&cutoff);
+        tokens.insert(*token, entry);
+        drop(tokens);
+
+        Ok(())
+    }
+
+    async fn verify(&self, token: &Ulid) -> Result<bool> {
+        Ok(self
+            .tokens
+            .read()
+            .map_err(|_| anyhow!("tokens lock poisoned"))?
+            .contains_key(token))
+    }
+
+    async fn record_verified(&self, token: &Ulid) -> Result<()> {
+        if let Some(entry) = self
+            .tokens
+            .write()
+            .map_err(|_| anyhow!("tokens lock poisoned"))?
+            .get_mut(token)
+        {
+            entry.verified_at.get_or_insert_with(Utc::now);
+        }
+
+        Ok(())
+    }
+
+    async fn consume(&self, token: &Ulid) -> Result<bool> {
+        self.revoke(token).await
+    }
+
+    async fn revoke(&self, token: &Ulid) -> Result<bool> {
+        Ok(self
+            .tokens
+            .write()
+            .map_err(|_| anyhow!("tokens lock poisoned"))?
+            .remove(token)
+            .is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(120);
+
+    #[tokio::test]
+    async fn test_insert_verify_revoke() {
+        let store = MemoryStore::new(TTL);
+        let token = Ulid::new();
+
+        assert!(!store.verify(&token).await.unwrap());
+
+        store.insert(&token, 1, &Metadata::default()).await.unwrap();
+        assert!(store.verify(&token).await.unwrap());
+        assert_eq!(store.metadata(&token).map(|(id, _)| id), Some(1));
+
+        assert!(store.revoke(&token).await.unwrap());
+        assert!(!store.revoke(&token).await.unwrap());
+        assert!(!store.verify(&token).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_record_verified() {
+        let store = MemoryStore::new(TTL);
+        let token = Ulid::new();
+
+        store.insert(&token, 0, &Metadata::default()).await.unwrap();
+        assert!(store.verified_at(&token).is_none());
+
+        // only the first verification is kept
+        store.record_verified(&token).await.unwrap();
+        let verified_at = store.verified_at(&token);
+        assert!(verified_at.is_some());
+
+        store.record_verified(&token).await.unwrap();
+        assert_eq!(store.verified_at(&token), verified_at);
+
+        // unknown tokens are ignored
+        store.record_verified(&Ulid::new()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_consume() {
+        let store = MemoryStore::new(TTL);
+        let token = Ulid::new();
+
+        store.insert(&token, 0, &Metadata::default()).await.unwrap();
+
+        // single use
+        assert!(store.consume(&token).await.unwrap());
+        assert!(!store.consume(&token).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_prune() {
+        let store = MemoryStore::new(TTL);
+        let now = Ulid::new().timestamp_ms();
+
+        let old = Ulid::from_parts(now - 121_000, 1);
+        let recent = Ulid::from_parts(now - 60_000, 1);
+
+        for token in [old, recent] {
+            store.insert(&token, 0, &Metadata::default()).await.unwrap();
+        }
+        assert_eq!(store.len(), 2);
+
+        store
+            .insert(&Ulid::from_parts(now, 1), 0, &Metadata::default())
+            .await
+            .unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(!store.verify(&old).await.unwrap());
+        assert!(store.verify(&recent).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_client() {
+        let store = MemoryStore::new(TTL);
+        let uuid = Uuid::new_v4();
+
+        assert!(store.lookup_client(uuid).await.unwrap().is_none());
+
+        store
+            .add_client(
+                uuid,
+                Client {
+                    id: 7,
+                    allowed_origins: vec!["https://permesi.dev".to_string()],
+                },
+            )
+            .unwrap();
+
+        assert_eq!(
+            store.lookup_client(uuid).await.unwrap().map(|c| c.id),
+            Some(7)
+        );
+    }
+}
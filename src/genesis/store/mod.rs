@@ -0,0 +1,108 @@
+use crate::cli::config::HeadersConfig;
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::http::HeaderMap;
+use std::{fmt, net::IpAddr};
+use ulid::Ulid;
+use uuid::Uuid;
+
+pub mod analytics;
+pub mod archive;
+pub mod memory;
+pub mod partitions;
+pub mod postgres;
+
+pub use self::{memory::MemoryStore, postgres::PgStore};
+
+/// Client registered in the `clients` table
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Client {
+    pub id: i16,
+    // CORS origins allowed to request tokens, empty uses the global ones
+    pub allowed_origins: Vec<String>,
+}
+
+/// Request metadata stored with the token
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub ip_address: Option<IpAddr>,
+    pub country: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl Metadata {
+    /// The IP address and country are set by the proxy/CDN, User-Agent is optional
+    #[must_use]
+    pub fn from_headers(config: &HeadersConfig, headers: &HeaderMap) -> Self {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(ToString::to_string)
+        };
+
+        Self {
+            ip_address: header(&config.ip).and_then(|ip| ip.parse::<IpAddr>().ok()),
+            country: header(&config.country),
+            user_agent: header("User-Agent"),
+        }
+    }
+}
+
+/// Storage of the issued tokens, the ULID timestamp is the time the token was
+/// issued, expiration is checked by the caller
+#[async_trait]
+pub trait TokenStore: fmt::Debug + Send + Sync {
+    /// Find the client by its uuid
+    async fn lookup_client(&self, uuid: Uuid) -> Result<Option<Client>>;
+
+    /// Store the token and its metadata
+    async fn insert(&self, token: &Ulid, client_id: i16, metadata: &Metadata) -> Result<()>;
+
+    /// Returns true if the token exists, read only
+    async fn verify(&self, token: &Ulid) -> Result<bool>;
+
+    /// Record the first verification of the token, for the analytics
+    async fn record_verified(&self, token: &Ulid) -> Result<()>;
+
+    /// Verify and delete the token so it can only be used once
+    async fn consume(&self, token: &Ulid) -> Result<bool>;
+
+    /// Delete the token, returns true if it existed
+    async fn revoke(&self, token: &Ulid) -> Result<bool>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_metadata_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("CF-Connecting-IP", HeaderValue::from_static("192.0.2.1"));
+        headers.insert("CF-IPCountry", HeaderValue::from_static("CH"));
+        headers.insert("User-Agent", HeaderValue::from_static("curl/8.0"));
+
+        let metadata = Metadata::from_headers(&HeadersConfig::default(), &headers);
+        assert_eq!(
+            metadata,
+            Metadata {
+                ip_address: "192.0.2.1".parse().ok(),
+                country: Some("CH".to_string()),
+                user_agent: Some("curl/8.0".to_string()),
+            }
+        );
+
+        // custom header names, invalid IP addresses are ignored
+        let config = HeadersConfig {
+            ip: "X-Real-IP".to_string(),
+            country: "X-Country".to_string(),
+        };
+        headers.insert("X-Real-IP", HeaderValue::from_static("not an ip"));
+
+        let metadata = Metadata::from_headers(&config, &headers);
+        assert_eq!(metadata.ip_address, None);
+        assert_eq!(metadata.country, None);
+    }
+}
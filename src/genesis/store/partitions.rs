@@ -0,0 +1,324 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, DurationRound, NaiveDateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{debug, error, info, warn};
+
+/// How often the partitions are checked
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Only one replica at a time creates or drops partitions
+const ADVISORY_LOCK: i64 = 0x0067_656e_6573_6973; // "genesis"
+
+/// Partitioned tables, `metadata` references `tokens` so it's dropped first
+const TABLES: [&str; 2] = ["tokens", "metadata"];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Interval {
+    #[default]
+    Hour,
+    Day,
+}
+
+impl Interval {
+    const fn delta(self) -> TimeDelta {
+        match self {
+            Self::Hour => TimeDelta::hours(1),
+            Self::Day => TimeDelta::days(1),
+        }
+    }
+
+    // suffix of the partition names, tokens_p2024022310 or tokens_p20240223
+    const fn format(self) -> &'static str {
+        match self {
+            Self::Hour => "%Y%m%d%H",
+            Self::Day => "%Y%m%d",
+        }
+    }
+
+    /// Start of the partition that contains `time`
+    fn floor(self, time: DateTime<Utc>) -> Result<DateTime<Utc>> {
+        time.duration_trunc(self.delta())
+            .map_err(|err| anyhow!("Failed to truncate {}: {}", time, err))
+    }
+
+    fn suffix(self, start: DateTime<Utc>) -> String {
+        start.format(self.format()).to_string()
+    }
+
+    // start of the partition from its name, None if not created by genesis
+    fn parse(self, name: &str) -> Option<DateTime<Utc>> {
+        let suffix = name.rsplit_once("_p")?.1;
+
+        let start = match self {
+            Self::Hour => NaiveDateTime::parse_from_str(&format!("{suffix}00"), "%Y%m%d%H%M"),
+            Self::Day => NaiveDateTime::parse_from_str(&format!("{suffix}0000"), "%Y%m%d%H%M"),
+        };
+
+        start.ok().map(|start| start.and_utc())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PartitionsConfig {
+    pub interval: Interval,
+    // partitions created ahead of time
+    pub premake: u32,
+    // past partitions kept, older ones are dropped
+    pub retention: u32,
+    // role that owns the partitions, needed when the database user changes
+    // (Vault dynamic credentials) so old partitions can still be dropped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner_role: Option<String>,
+}
+
+impl Default for PartitionsConfig {
+    fn default() -> Self {
+        Self {
+            interval: Interval::default(),
+            premake: 4,
+            retention: 24,
+            owner_role: None,
+        }
+    }
+}
+
+impl PartitionsConfig {
+    /// # Errors
+    /// Will return an error if no partitions would be created or kept
+    pub fn validate(&self) -> Result<()> {
+        if self.premake == 0 {
+            return Err(anyhow!("partitions premake must be at least 1"));
+        }
+
+        if self.retention == 0 {
+            return Err(anyhow!("partitions retention must be at least 1"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Partitions to create and drop
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Plan {
+    // suffix, from, to
+    pub create: Vec<(String, DateTime<Utc>, DateTime<Utc>)>,
+    pub drop: Vec<String>,
+}
+
+/// Compare the existing partitions with the ones needed at `now`
+/// # Errors
+/// Will return an error if `now` can't be truncated to the interval
+pub fn plan(config: &PartitionsConfig, now: DateTime<Utc>, existing: &[String]) -> Result<Plan> {
+    let interval = config.interval;
+    let current = interval.floor(now)?;
+
+    let mut plan = Plan::default();
+
+    // the current partition and the ones ahead
+    let mut start = current;
+    for _ in 0..=config.premake {
+        let suffix = interval.suffix(start);
+        let end = start + interval.delta();
+
+        if !existing
+            .iter()
+            .any(|name| name.ends_with(&format!("_p{suffix}")))
+        {
+            plan.create.push((suffix, start, end));
+        }
+
+        start = end;
+    }
+
+    // drop the partitions that ended before the retention
+    let oldest = current - interval.delta() * i32::try_from(config.retention).unwrap_or(i32::MAX);
+
+    for name in existing {
+        if let Some(start) = interval.parse(name) {
+            if start < oldest {
+                plan.drop.push(interval.suffix(start));
+            }
+        }
+    }
+
+    plan.drop.sort();
+    plan.drop.dedup();
+
+    Ok(plan)
+}
+
+/// Create the partitions ahead of time and drop the expired ones, with
+/// `archive` partitions are only dropped once the archiver emptied them
+/// # Errors
+/// Will return an error if the partitions can't be listed, created or dropped
+pub async fn maintain(
+    pool: &PgPool,
+    config: &PartitionsConfig,
+    archive: bool,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    let mut tx = pool.begin().await?;
+
+    let locked: bool = sqlx::query("SELECT pg_try_advisory_xact_lock($1) AS locked")
+        .bind(ADVISORY_LOCK)
+        .fetch_one(&mut *tx)
+        .await?
+        .get("locked");
+
+    if !locked {
+        debug!("Partition maintenance running on another replica");
+        return Ok(());
+    }
+
+    if let Some(role) = &config.owner_role {
+        sqlx::query(&format!("SET LOCAL ROLE {}", quote(role)))
+            .execute(&mut *tx)
+            .await
+            .context("Failed to set the partitions owner role")?;
+    }
+
+    let existing: Vec<String> = sqlx::query(
+        "SELECT c.relname FROM pg_inherits i JOIN pg_class c ON c.oid = i.inhrelid WHERE i.inhparent = 'tokens'::regclass",
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .iter()
+    .map(|row| row.get("relname"))
+    .collect();
+
+    let plan = plan(config, now, &existing)?;
+
+    for (suffix, from, to) in &plan.create {
+        for table in TABLES {
+            sqlx::query(&format!(
+                "CREATE TABLE IF NOT EXISTS {table}_p{suffix} PARTITION OF {table} FOR VALUES FROM ('{}') TO ('{}')",
+                from.to_rfc3339(),
+                to.to_rfc3339()
+            ))
+            .execute(&mut *tx)
+            .await
+            .with_context(|| format!("Failed to create partition {table}_p{suffix}"))?;
+        }
+
+        info!("Created partitions for {}", from);
+    }
+
+    for suffix in &plan.drop {
+        if archive {
+            let pending: bool = sqlx::query(&format!(
+                "SELECT EXISTS(SELECT 1 FROM tokens_p{suffix}) AS pending"
+            ))
+            .fetch_one(&mut *tx)
+            .await?
+            .get("pending");
+
+            if pending {
+                warn!("Partition tokens_p{} not archived yet, keeping it", suffix);
+                continue;
+            }
+        }
+
+        // metadata first, tokens must be detached before it can be dropped
+        for statement in [
+            format!("DROP TABLE IF EXISTS metadata_p{suffix}"),
+            format!("ALTER TABLE tokens DETACH PARTITION tokens_p{suffix}"),
+            format!("DROP TABLE IF EXISTS tokens_p{suffix}"),
+        ] {
+            sqlx::query(&statement)
+                .execute(&mut *tx)
+                .await
+                .with_context(|| format!("Failed to drop partition {suffix}"))?;
+        }
+
+        info!("Dropped partitions {}", suffix);
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Run the maintenance periodically
+pub fn spawn(pool: PgPool, config: PartitionsConfig, archive: bool) {
+    tokio::spawn(async move {
+        loop {
+            sleep(MAINTENANCE_INTERVAL).await;
+
+            if let Err(err) = maintain(&pool, &config, archive, Utc::now()).await {
+                error!("Partition maintenance failed: {:#}", err);
+            }
+        }
+    });
+}
+
+fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 2, 23, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_interval() {
+        assert_eq!(Interval::Hour.floor(at(10, 46)).unwrap(), at(10, 0));
+        assert_eq!(Interval::Day.floor(at(10, 46)).unwrap(), at(0, 0));
+        assert_eq!(Interval::Hour.suffix(at(10, 0)), "2024022310");
+        assert_eq!(Interval::Day.suffix(at(0, 0)), "20240223");
+        assert_eq!(Interval::Hour.parse("tokens_p2024022310"), Some(at(10, 0)));
+        assert_eq!(Interval::Day.parse("tokens_p20240223"), Some(at(0, 0)));
+        assert_eq!(Interval::Hour.parse("tokens_p20240223"), None);
+        assert_eq!(Interval::Hour.parse("tokens_default"), None);
+    }
+
+    #[test]
+    fn test_plan() {
+        let config = PartitionsConfig {
+            premake: 2,
+            retention: 2,
+            ..PartitionsConfig::default()
+        };
+
+        let existing = vec![
+            "tokens_p2024022306".to_string(),
+            "tokens_p2024022307".to_string(),
+            "tokens_p2024022308".to_string(),
+            "tokens_p2024022310".to_string(),
+            "tokens_p2024022311".to_string(),
+        ];
+
+        let plan = plan(&config, at(10, 46), &existing).unwrap();
+
+        assert_eq!(
+            plan.create,
+            vec![("2024022312".to_string(), at(12, 0), at(13, 0))]
+        );
+        // keep 08 and 09
+        assert_eq!(plan.drop, vec!["2024022306", "2024022307"]);
+    }
+
+    #[test]
+    fn test_plan_empty() {
+        let plan = plan(&PartitionsConfig::default(), at(10, 46), &[]).unwrap();
+        assert_eq!(plan.create.len(), 5);
+        assert_eq!(plan.create[0].1, at(10, 0));
+        assert!(plan.drop.is_empty());
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("genesis"), "\"genesis\"");
+        assert_eq!(quote("a\"b"), "\"a\"\"b\"");
+    }
+}
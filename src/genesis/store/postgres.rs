@@ -0,0 +1,232 @@
+use crate::genesis::store::{Client, Metadata, TokenStore};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    postgres::{PgArguments, PgDatabaseError},
+    query::Query,
+    PgPool, Postgres, Row,
+};
+use tracing::{debug, error};
+use ulid::Ulid;
+use uuid::Uuid;
+
+// SQLSTATE of insufficient_privilege
+const INSUFFICIENT_PRIVILEGE: &str = "42501";
+
+// SQLSTATE of undefined_column
+const UNDEFINED_COLUMN: &str = "42703";
+
+/// Whether the database rejected the query because the user lacks a
+/// privilege, e.g. the Vault credentials were revoked or the schema changed
+#[must_use]
+pub fn insufficient_privilege(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.downcast_ref::<sqlx::Error>().and_then(code) == Some(INSUFFICIENT_PRIVILEGE)
+    })
+}
+
+// SQLSTATE of a database error
+fn code(err: &sqlx::Error) -> Option<&str> {
+    match err {
+        sqlx::Error::Database(db_err) => db_err
+            .as_error()
+            .downcast_ref::<PgDatabaseError>()
+            .map(PgDatabaseError::code),
+        _ => None,
+    }
+}
+
+/// How the tokens are stored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// `ulid` columns, requires the `pgx_ulid` extension (sql/schema.sql)
+    #[default]
+    Ulid,
+    /// ULID stored as `uuid` and the issue time in `issued_at`, no extensions
+    /// needed (sql/schema-portable.sql)
+    Portable,
+    /// portable layout with the tables partitioned by `issued_at`
+    /// (sql/schema-partitioned.sql)
+    Partitioned,
+}
+
+/// Tokens stored in Postgres
+#[derive(Debug, Clone)]
+pub struct PgStore {
+    pool: PgPool,
+    layout: Layout,
+}
+
+impl PgStore {
+    #[must_use]
+    pub const fn new(pool: PgPool, layout: Layout) -> Self {
+        Self { pool, layout }
+    }
+
+    // query with the token bound as $1 in the type of the layout, partitioned
+    // tables also get the issue time as $2 so only one partition is scanned
+    fn query<'q>(
+        &self,
+        ulid: &'q str,
+        portable: &'q str,
+        partitioned: &'q str,
+        token: &Ulid,
+    ) -> Query<'q, Postgres, PgArguments> {
+        match self.layout {
+            Layout::Ulid => sqlx::query(ulid).bind(token.to_string()),
+            Layout::Portable => sqlx::query(portable).bind(Uuid::from_u128(token.0)),
+            Layout::Partitioned => sqlx::query(partitioned)
+                .bind(Uuid::from_u128(token.0))
+                .bind(DateTime::<Utc>::from(token.datetime())),
+        }
+    }
+}
+
+#[async_trait]
+impl TokenStore for PgStore {
+    async fn lookup_client(&self, uuid: Uuid) -> Result<Option<Client>> {
+        let query = "SELECT id, allowed_origins FROM clients WHERE uuid = $1";
+
+        match sqlx::query(query)
+            .bind(uuid)
+            .fetch_optional(&self.pool)
+            .await
+        {
+            Ok(Some(row)) => Ok(Some(Client {
+                id: row.try_get("id")?,
+                allowed_origins: row
+                    .try_get::<Option<Vec<String>>, _>("allowed_origins")?
+                    .unwrap_or_default(),
+            })),
+
+            Ok(None) => Ok(None),
+
+            // without migration 0003 the clients have no registered origins
+            Err(err) if code(&err) == Some(UNDEFINED_COLUMN) => {
+                debug!("Clients have no allowed_origins column: {}", err);
+
+                let query = "SELECT id FROM clients WHERE uuid = $1";
+
+                match sqlx::query(query)
+                    .bind(uuid)
+                    .fetch_optional(&self.pool)
+                    .await?
+                {
+                    Some(row) => Ok(Some(Client {
+                        id: row.try_get("id")?,
+                        allowed_origins: Vec::new(),
+                    })),
+                    None => Ok(None),
+                }
+            }
+
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn insert(&self, token: &Ulid, client_id: i16, metadata: &Metadata) -> Result<()> {
+        // start transaction
+        let mut tx = self.pool.begin().await.map_err(|err| {
+            error!("Failed to start transaction: {}", err);
+            err
+        })?;
+
+        let insert = match self.layout {
+            Layout::Ulid => sqlx::query("INSERT INTO tokens (id, client_id) VALUES ($1::ulid, $2)")
+                .bind(token.to_string())
+                .bind(client_id),
+            Layout::Portable | Layout::Partitioned => {
+                sqlx::query("INSERT INTO tokens (id, client_id, issued_at) VALUES ($1, $2, $3)")
+                    .bind(Uuid::from_u128(token.0))
+                    .bind(client_id)
+                    .bind(DateTime::<Utc>::from(token.datetime()))
+            }
+        };
+
+        let result = match insert.execute(&mut *tx).await {
+            Ok(_) => {
+                self.query(
+                    "INSERT INTO metadata (id, ip_address, country, user_agent) VALUES ($1::ulid, $2, $3, $4)",
+                    "INSERT INTO metadata (id, ip_address, country, user_agent) VALUES ($1, $2, $3, $4)",
+                    "INSERT INTO metadata (id, issued_at, ip_address, country, user_agent) VALUES ($1, $2, $3, $4, $5)",
+                    token,
+                )
+                .bind(metadata.ip_address)
+                .bind(metadata.country.as_deref())
+                .bind(metadata.user_agent.as_deref())
+                .execute(&mut *tx)
+                .await
+            }
+
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(_) => tx.commit().await.map_err(|err| {
+                error!("Failed to commit transaction: {}", err);
+                err.into()
+            }),
+
+            Err(err) => {
+                match tx.rollback().await {
+                    Ok(()) => debug!("Rolled back transaction"),
+
+                    Err(err) => error!("Failed to rollback transaction: {}", err),
+                };
+
+                error!("Failed to insert token into database: {}", err);
+
+                Err(err.into())
+            }
+        }
+    }
+
+    async fn verify(&self, token: &Ulid) -> Result<bool> {
+        let row = self
+            .query(
+                "SELECT 1 FROM tokens WHERE id = $1::ulid",
+                "SELECT 1 FROM tokens WHERE id = $1",
+                "SELECT 1 FROM tokens WHERE id = $1 AND issued_at = $2",
+                token,
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.is_some())
+    }
+
+    async fn record_verified(&self, token: &Ulid) -> Result<()> {
+        self.query(
+            "UPDATE tokens SET verified_at = NOW() WHERE id = $1::ulid AND verified_at IS NULL",
+            "UPDATE tokens SET verified_at = NOW() WHERE id = $1 AND verified_at IS NULL",
+            "UPDATE tokens SET verified_at = NOW() WHERE id = $1 AND issued_at = $2 AND verified_at IS NULL",
+            token,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn consume(&self, token: &Ulid) -> Result<bool> {
+        // the metadata is deleted by the cascade
+        self.revoke(token).await
+    }
+
+    async fn revoke(&self, token: &Ulid) -> Result<bool> {
+        let result = self
+            .query(
+                "DELETE FROM tokens WHERE id = $1::ulid",
+                "DELETE FROM tokens WHERE id = $1",
+                "DELETE FROM tokens WHERE id = $1 AND issued_at = $2",
+                token,
+            )
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
@@ -0,0 +1,235 @@
+use crate::{
+    cli::globals::GlobalArgs,
+    vault::{self, status::Status},
+};
+use anyhow::{anyhow, Context, Result};
+use secrecy::ExposeSecret;
+use sqlx::PgPool;
+use std::sync::Arc;
+use tokio::{
+    sync::{mpsc, watch},
+    time::{Duration, Instant},
+};
+use tracing::{error, info, instrument, warn};
+
+/// Lifetime of the pool connections, the connections in use during a refresh
+/// keep the old credentials until then
+pub const POOL_MAX_LIFETIME: Duration = Duration::from_secs(60 * 2);
+
+// reports until the old connections are closed come from requests still using
+// them, with a margin for the queries running at that time
+const SETTLE_PERIOD: Duration = Duration::from_secs(POOL_MAX_LIFETIME.as_secs() + 10);
+
+// failing again this soon means new credentials don't help
+const RETRY_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// Handle given to the handlers to report that the database rejected the
+/// credentials
+#[derive(Debug, Clone)]
+pub struct Supervisor {
+    tx: mpsc::Sender<()>,
+}
+
+impl Supervisor {
+    /// The supervisor and the receiver to pass to `spawn`
+    #[must_use]
+    pub fn new() -> (Self, mpsc::Receiver<()>) {
+        // a burst of failing requests is a single report
+        let (tx, rx) = mpsc::channel(1);
+
+        (Self { tx }, rx)
+    }
+
+    /// Report that the database user lacks privileges, never blocks the request
+    pub fn credentials_invalid(&self) {
+        let _ = self.tx.try_send(());
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Decision {
+    Ignore,
+    Refresh,
+    Shutdown,
+}
+
+// what to do with a report, given when the credentials were last refreshed
+fn decide(last_refresh: Option<Instant>, now: Instant) -> Decision {
+    match last_refresh.map(|last| now.duration_since(last)) {
+        Some(elapsed) if elapsed < SETTLE_PERIOD => Decision::Ignore,
+        Some(elapsed) if elapsed < RETRY_WINDOW => Decision::Shutdown,
+        _ => Decision::Refresh,
+    }
+}
+
+/// Handle the reports: fetch new DB credentials from Vault and use them for
+/// the new connections of the pool, or shut down gracefully when that is not
+/// possible (dev mode) or doesn't help.
+///
+/// `db_lease` is updated with the new lease so it gets renewed and revoked on
+/// shutdown instead of the old one.
+pub fn spawn(
+    mut rx: mpsc::Receiver<()>,
+    pool: PgPool,
+    mut globals: GlobalArgs,
+    status: Arc<Status>,
+    db_lease: watch::Sender<String>,
+    shutdown: mpsc::UnboundedSender<&'static str>,
+) {
+    tokio::spawn(async move {
+        let mut last_refresh = None;
+
+        while rx.recv().await.is_some() {
+            warn!("Database credentials are invalid");
+
+            if globals.dev {
+                let _ =
+                    shutdown.send("Database credentials are invalid, dev mode can't renew them");
+                return;
+            }
+
+            match decide(last_refresh, Instant::now()) {
+                Decision::Ignore => continue,
+                Decision::Shutdown => {
+                    let _ = shutdown.send("Database credentials are still invalid after a refresh");
+                    return;
+                }
+                Decision::Refresh => {}
+            }
+
+            match refresh(&pool, &mut globals, &status, &db_lease).await {
+                Ok(()) => last_refresh = Some(Instant::now()),
+                Err(e) => {
+                    error!("Failed to refresh database credentials: {:#}", e);
+                    let _ = shutdown.send("Failed to refresh database credentials");
+                    return;
+                }
+            }
+        }
+    });
+}
+
+// new credentials for the new connections, the idle ones are closed and the
+// ones in use are closed by `max_lifetime`
+#[instrument(skip_all)]
+async fn refresh(
+    pool: &PgPool,
+    globals: &mut GlobalArgs,
+    status: &Status,
+    db_lease: &watch::Sender<String>,
+) -> Result<()> {
+    // the agent renews the token in the file, use the latest one
+    if let Some(path) = &globals.vault_token_file {
+        globals.vault_token = vault::token_file::read(path)?;
+    }
+
+    let old_lease = globals.vault_db_lease_id.clone();
+
+    vault::database::database_creds(globals).await?;
+
+    let options = (*pool.connect_options())
+        .clone()
+        .username(&globals.vault_db_username)
+        .password(globals.vault_db_password.expose_secret());
+
+    pool.set_connect_options(options);
+
+    while let Some(conn) = pool.try_acquire() {
+        if let Err(e) = conn.close().await {
+            warn!("Failed to close connection: {}", e);
+        }
+    }
+
+    sqlx::query("SELECT 1")
+        .execute(pool)
+        .await
+        .context("Failed to connect with the new credentials")?;
+
+    status.db_lease_renewed(globals.vault_db_lease_duration);
+
+    db_lease
+        .send(globals.vault_db_lease_id.clone())
+        .map_err(|_| anyhow!("DB lease renewal stopped"))?;
+
+    info!("Refreshed database credentials");
+
+    if let Err(e) =
+        vault::revoke::revoke_lease(&globals.vault_url, &globals.vault_token, &old_lease).await
+    {
+        warn!("Failed to revoke the old DB lease: {}", e);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decide() {
+        let now = Instant::now();
+
+        assert_eq!(decide(None, now), Decision::Refresh);
+        assert_eq!(
+            decide(Some(now), now + Duration::from_secs(1)),
+            Decision::Ignore
+        );
+        // an old connection may still be in use
+        assert_eq!(decide(Some(now), now + POOL_MAX_LIFETIME), Decision::Ignore);
+        assert_eq!(
+            decide(Some(now), now + Duration::from_secs(180)),
+            Decision::Shutdown
+        );
+        assert_eq!(
+            decide(Some(now), now + Duration::from_secs(600)),
+            Decision::Refresh
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reports_coalesced() {
+        let (supervisor, mut rx) = Supervisor::new();
+
+        supervisor.credentials_invalid();
+        supervisor.credentials_invalid();
+
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+    }
+
+    async fn reason(globals: GlobalArgs) -> &'static str {
+        let (supervisor, rx) = Supervisor::new();
+        let (shutdown_tx, mut shutdown_rx) = mpsc::unbounded_channel();
+        let pool = PgPool::connect_lazy("postgres://genesis@localhost/genesis").unwrap();
+
+        spawn(
+            rx,
+            pool,
+            globals,
+            Arc::new(Status::disabled()),
+            watch::channel(String::new()).0,
+            shutdown_tx,
+        );
+
+        supervisor.credentials_invalid();
+
+        shutdown_rx.recv().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_dev_shuts_down() {
+        assert!(reason(GlobalArgs::dev()).await.contains("dev mode"));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_failed_shuts_down() {
+        // nothing listens there
+        let globals = GlobalArgs::new("http://127.0.0.1:1".to_string());
+
+        assert_eq!(
+            reason(globals).await,
+            "Failed to refresh database credentials"
+        );
+    }
+}
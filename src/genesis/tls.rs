@@ -0,0 +1,198 @@
+use anyhow::{anyhow, Context, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+use tokio::time::{sleep, Duration};
+use tracing::{error, info, instrument};
+
+/// How often the certificate and key files are checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // offer HTTP/2 via ALPN
+    #[serde(default)]
+    pub http2: bool,
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path)
+        .with_context(|| format!("Could not open certificate {}", path.display()))?;
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Could not parse certificate {}", path.display()))?;
+
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {}", path.display()));
+    }
+
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file =
+        File::open(path).with_context(|| format!("Could not open key {}", path.display()))?;
+
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("Could not parse key {}", path.display()))?
+        .ok_or_else(|| anyhow!("No private key found in {}", path.display()))
+}
+
+/// Build the rustls server config from the PEM files, client certificates
+/// signed by `client_ca` are required when set
+/// # Errors
+/// Will return an error if the certificate, key or client CA can't be loaded
+pub fn server_config(tls: &TlsConfig, client_ca: Option<&Path>) -> Result<ServerConfig> {
+    let certs = load_certs(&tls.cert)?;
+    let key = load_key(&tls.key)?;
+
+    let provider = Arc::new(ring::default_provider());
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+
+            for cert in load_certs(client_ca)? {
+                roots.add(cert)?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .context("Invalid client CA")?;
+
+            builder.with_client_cert_verifier(verifier)
+        }
+
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .context("Invalid certificate or key")?;
+
+    config.alpn_protocols = alpn_protocols(tls.http2);
+
+    Ok(config)
+}
+
+fn alpn_protocols(http2: bool) -> Vec<Vec<u8>> {
+    if http2 {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    }
+}
+
+fn modified(tls: &TlsConfig, client_ca: Option<&Path>) -> Vec<Option<SystemTime>> {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+    [Some(tls.cert.as_path()), Some(tls.key.as_path()), client_ca]
+        .into_iter()
+        .flatten()
+        .map(modified)
+        .collect()
+}
+
+/// Load the certificate and reload it when the files change, e.g. when
+/// cert-manager rotates the secret
+/// # Errors
+/// Will return an error if the certificate, key or client CA can't be loaded
+#[instrument]
+pub fn rustls_config(tls: TlsConfig, client_ca: Option<PathBuf>) -> Result<RustlsConfig> {
+    let config = RustlsConfig::from_config(Arc::new(server_config(&tls, client_ca.as_deref())?));
+
+    tokio::spawn({
+        let config = config.clone();
+
+        async move {
+            let mut last_modified = modified(&tls, client_ca.as_deref());
+
+            loop {
+                sleep(POLL_INTERVAL).await;
+
+                let current = modified(&tls, client_ca.as_deref());
+
+                if current == last_modified {
+                    continue;
+                }
+
+                // keep serving the previous certificate if the new one is broken
+                match server_config(&tls, client_ca.as_deref()) {
+                    Ok(server_config) => {
+                        config.reload_from_config(Arc::new(server_config));
+
+                        last_modified = current;
+
+                        info!("Reloaded TLS certificate {}", tls.cert.display());
+                    }
+
+                    Err(e) => error!("Failed to reload TLS certificate: {:#}", e),
+                }
+            }
+        }
+    });
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_cert(dir: &Path) -> TlsConfig {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+        let tls = TlsConfig {
+            cert: dir.join("tls.crt"),
+            key: dir.join("tls.key"),
+            http2: true,
+        };
+
+        std::fs::write(&tls.cert, cert.cert.pem()).unwrap();
+        std::fs::write(&tls.key, cert.key_pair.serialize_pem()).unwrap();
+
+        tls
+    }
+
+    #[test]
+    fn test_server_config() {
+        let dir = std::env::temp_dir().join(format!("genesis-tls-{}", ulid::Ulid::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut tls = write_cert(&dir);
+
+        let config = server_config(&tls, None).unwrap();
+        assert_eq!(config.alpn_protocols, alpn_protocols(true));
+
+        tls.http2 = false;
+        let config = server_config(&tls, None).unwrap();
+        assert_eq!(config.alpn_protocols, vec![b"http/1.1".to_vec()]);
+
+        // the self-signed certificate doubles as client CA
+        assert!(server_config(&tls, Some(&tls.cert)).is_ok());
+
+        std::fs::write(&tls.key, "").unwrap();
+        assert!(server_config(&tls, None).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,5 @@
 use crate::{cli::globals::GlobalArgs, vault};
 use anyhow::{anyhow, Result};
-use reqwest::Client;
 use secrecy::{ExposeSecret, SecretString};
 use serde_json::Value;
 use tracing::instrument;
@@ -8,9 +7,7 @@ use tracing::instrument;
 /// Get DB credentials from Vault
 #[instrument]
 pub async fn database_creds(globals: &mut GlobalArgs) -> Result<()> {
-    let client = Client::builder()
-        .user_agent(vault::APP_USER_AGENT)
-        .build()?;
+    let client = vault::client()?;
 
     // Parse the URL
     let db_creds = vault::endpoint_url(&globals.vault_url, "/v1/database/creds/genesis")?;
@@ -57,3 +54,54 @@ pub async fn database_creds(globals: &mut GlobalArgs) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::mock::{MockVault, Reply, DATABASE_CREDS, TOKEN};
+    use reqwest::StatusCode;
+
+    #[tokio::test]
+    async fn test_database_creds() {
+        let vault = MockVault::start().await;
+
+        vault.script(
+            DATABASE_CREDS,
+            [Reply::creds(
+                "database/creds/genesis/1",
+                600,
+                "v-genesis",
+                "pass",
+            )],
+        );
+
+        let mut globals = GlobalArgs::new(vault.login_url());
+        globals.set_token(SecretString::from(TOKEN));
+
+        database_creds(&mut globals).await.unwrap();
+        assert_eq!(globals.vault_db_lease_id, "database/creds/genesis/1");
+        assert_eq!(globals.vault_db_lease_duration, 600);
+        assert_eq!(globals.vault_db_username, "v-genesis");
+        assert_eq!(globals.vault_db_password.expose_secret(), "pass");
+        assert_eq!(vault.calls(DATABASE_CREDS)[0].token.as_deref(), Some(TOKEN));
+
+        vault.script(
+            DATABASE_CREDS,
+            [Reply::error(StatusCode::FORBIDDEN, "permission denied")],
+        );
+        let err = database_creds(&mut globals).await.unwrap_err();
+        assert!(err.to_string().contains("403 Forbidden, permission denied"));
+
+        // a role without a password
+        vault.script(
+            DATABASE_CREDS,
+            [Reply::Json(serde_json::json!({
+                "lease_id": "database/creds/genesis/2",
+                "lease_duration": 600,
+                "data": { "username": "v-genesis" }
+            }))],
+        );
+        let err = database_creds(&mut globals).await.unwrap_err();
+        assert!(err.to_string().contains("no password found"));
+    }
+}
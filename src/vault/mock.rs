@@ -0,0 +1,181 @@
+//! Vault stand-in for the tests
+//!
+//! Each path answers with its scripted replies in order, the last one is
+//! repeated. The calls are recorded to check what genesis sent.
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json, Router,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+pub const LOGIN: &str = "/v1/auth/approle/login";
+pub const UNWRAP: &str = "/v1/sys/wrapping/unwrap";
+pub const DATABASE_CREDS: &str = "/v1/database/creds/genesis";
+pub const RENEW_SELF: &str = "/v1/auth/token/renew-self";
+pub const RENEW_LEASE: &str = "/v1/sys/leases/renew";
+pub const REVOKE_LEASE: &str = "/v1/sys/leases/revoke";
+pub const REVOKE_SELF: &str = "/v1/auth/token/revoke-self";
+
+pub const TOKEN: &str = "hvs.mock";
+pub const SECRET_ID: &str = "mock-secret-id";
+pub const LEASE_ID: &str = "database/creds/genesis/mock";
+
+#[derive(Debug, Clone)]
+pub enum Reply {
+    Json(Value),
+    // Vault errors are {"errors": [...]}
+    Error(StatusCode, String),
+    NoContent,
+}
+
+impl Reply {
+    /// AppRole login or token renewal
+    pub fn auth(token: &str, lease_duration: u64) -> Self {
+        Self::Json(json!({
+            "auth": { "client_token": token, "lease_duration": lease_duration }
+        }))
+    }
+
+    pub fn secret_id(secret_id: &str) -> Self {
+        Self::Json(json!({ "data": { "secret_id": secret_id } }))
+    }
+
+    pub fn creds(lease_id: &str, lease_duration: u64, username: &str, password: &str) -> Self {
+        Self::Json(json!({
+            "lease_id": lease_id,
+            "lease_duration": lease_duration,
+            "data": { "username": username, "password": password }
+        }))
+    }
+
+    /// Lease renewal
+    pub fn lease(lease_duration: u64) -> Self {
+        Self::Json(json!({ "lease_id": LEASE_ID, "lease_duration": lease_duration }))
+    }
+
+    pub fn error(status: StatusCode, error: &str) -> Self {
+        Self::Error(status, error.to_string())
+    }
+}
+
+impl IntoResponse for Reply {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Json(body) => Json(body).into_response(),
+            Self::Error(status, error) => {
+                (status, Json(json!({ "errors": [error] }))).into_response()
+            }
+            Self::NoContent => StatusCode::NO_CONTENT.into_response(),
+        }
+    }
+}
+
+/// A request received by the mock
+#[derive(Debug, Clone)]
+pub struct Call {
+    pub method: Method,
+    pub token: Option<String>,
+    pub body: Value,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    replies: HashMap<String, VecDeque<Reply>>,
+    calls: HashMap<String, Vec<Call>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MockVault {
+    pub url: String,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl MockVault {
+    /// Listen on a random port, every call succeeds with hour long leases
+    pub async fn start() -> Self {
+        let inner = Arc::new(Mutex::new(Inner::default()));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let app = Router::new().fallback(handle).with_state(inner.clone());
+
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let vault = Self { url, inner };
+
+        vault.script(LOGIN, [Reply::auth(TOKEN, 3600)]);
+        vault.script(UNWRAP, [Reply::secret_id(SECRET_ID)]);
+        vault.script(
+            DATABASE_CREDS,
+            [Reply::creds(LEASE_ID, 3600, "genesis", "secret")],
+        );
+        vault.script(RENEW_SELF, [Reply::auth(TOKEN, 3600)]);
+        vault.script(RENEW_LEASE, [Reply::lease(3600)]);
+        vault.script(REVOKE_LEASE, [Reply::NoContent]);
+        vault.script(REVOKE_SELF, [Reply::NoContent]);
+
+        vault
+    }
+
+    /// The AppRole login URL, used as the Vault URL
+    pub fn login_url(&self) -> String {
+        format!("{}{LOGIN}", self.url)
+    }
+
+    /// Replace the replies of a path
+    pub fn script(&self, path: &str, replies: impl IntoIterator<Item = Reply>) {
+        self.inner
+            .lock()
+            .unwrap()
+            .replies
+            .insert(path.to_string(), replies.into_iter().collect());
+    }
+
+    pub fn calls(&self, path: &str) -> Vec<Call> {
+        self.inner
+            .lock()
+            .unwrap()
+            .calls
+            .get(path)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+async fn handle(
+    State(inner): State<Arc<Mutex<Inner>>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Reply {
+    let path = uri.path().to_string();
+
+    let call = Call {
+        method,
+        token: headers
+            .get("x-vault-token")
+            .and_then(|token| token.to_str().ok())
+            .map(ToString::to_string),
+        body: serde_json::from_slice(&body).unwrap_or(Value::Null),
+    };
+
+    let mut inner = inner.lock().unwrap();
+
+    inner.calls.entry(path.clone()).or_default().push(call);
+
+    match inner.replies.get_mut(&path) {
+        Some(replies) if replies.len() > 1 => replies.pop_front().unwrap(),
+        Some(replies) if !replies.is_empty() => replies[0].clone(),
+        _ => Reply::error(StatusCode::NOT_FOUND, "no handler for route"),
+    }
+}
@@ -1,6 +1,12 @@
 pub mod database;
+#[cfg(test)]
+pub mod mock;
 pub mod renew;
+pub mod revoke;
+pub mod status;
+pub mod token_file;
 
+use crate::cli::telemetry::propagation;
 use anyhow::{anyhow, Result};
 use reqwest::Client;
 use serde_json::{json, Value};
@@ -10,6 +16,16 @@ use url::Url;
 
 static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
 
+/// HTTP client for Vault, requests continue the current trace (`traceparent`)
+/// # Errors
+/// Will return an error if the client can't be built
+pub fn client() -> Result<Client> {
+    Ok(Client::builder()
+        .user_agent(APP_USER_AGENT)
+        .default_headers(propagation::inject())
+        .build()?)
+}
+
 #[instrument]
 pub fn endpoint_url(url: &str, path: &str) -> Result<String> {
     let url = Url::parse(url)?;
@@ -42,7 +58,7 @@ pub fn endpoint_url(url: &str, path: &str) -> Result<String> {
 /// vault write -wrap-ttl=300s -f auth/approle/role/genesis/secret-id
 #[instrument]
 pub async fn unwrap(url: &str, token: &str) -> Result<String> {
-    let client = Client::builder().user_agent(APP_USER_AGENT).build()?;
+    let client = client()?;
 
     let unwrap_url = endpoint_url(url, "/v1/sys/wrapping/unwrap")?;
 
@@ -77,7 +93,7 @@ pub async fn unwrap(url: &str, token: &str) -> Result<String> {
 /// vault write -f auth/approle/role/genesis/secret-id
 #[instrument]
 pub async fn approle_login(url: &str, sid: &str, rid: &str) -> Result<(String, u64)> {
-    let client = Client::builder().user_agent(APP_USER_AGENT).build()?;
+    let client = client()?;
 
     // Create a JSON payload for AppRole login
     let login_payload = json!({
@@ -112,3 +128,91 @@ pub async fn approle_login(url: &str, sid: &str, rid: &str) -> Result<(String, u
 
     Ok((token.to_string(), lease_duration))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::{MockVault, Reply, LOGIN, UNWRAP};
+    use reqwest::StatusCode;
+
+    #[test]
+    fn test_endpoint_url() {
+        assert_eq!(
+            endpoint_url(
+                "https://vault.tld/v1/auth/approle/login",
+                "/v1/sys/leases/renew"
+            )
+            .unwrap(),
+            "https://vault.tld:443/v1/sys/leases/renew"
+        );
+        assert_eq!(
+            endpoint_url("http://127.0.0.1:8200/v1/auth/approle/login", "/v1/x").unwrap(),
+            "http://127.0.0.1:8200/v1/x"
+        );
+        assert!(endpoint_url("ftp://vault.tld", "/v1/x").is_err());
+        assert!(endpoint_url("vault.tld", "/v1/x").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unwrap() {
+        let vault = MockVault::start().await;
+
+        let secret_id = unwrap(&vault.login_url(), "hvs.wrapped").await.unwrap();
+        assert_eq!(secret_id, mock::SECRET_ID);
+        assert_eq!(vault.calls(UNWRAP)[0].token.as_deref(), Some("hvs.wrapped"));
+
+        vault.script(
+            UNWRAP,
+            [Reply::error(
+                StatusCode::BAD_REQUEST,
+                "wrapping token is not valid or does not exist",
+            )],
+        );
+
+        let err = unwrap(&vault.login_url(), "hvs.wrapped").await.unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("400 Bad Request, wrapping token is not valid"));
+    }
+
+    #[tokio::test]
+    async fn test_approle_login() {
+        let vault = MockVault::start().await;
+
+        vault.script(LOGIN, [Reply::auth("hvs.login", 60)]);
+
+        let (token, lease_duration) = approle_login(&vault.login_url(), "sid", "rid")
+            .await
+            .unwrap();
+        assert_eq!(token, "hvs.login");
+        assert_eq!(lease_duration, 60);
+
+        let call = &vault.calls(LOGIN)[0];
+        assert_eq!(call.body["role_id"], "rid");
+        assert_eq!(call.body["secret_id"], "sid");
+
+        // Vault always sends it, 30 minutes otherwise
+        vault.script(
+            LOGIN,
+            [Reply::Json(
+                json!({ "auth": { "client_token": "hvs.login" } }),
+            )],
+        );
+        let (_, lease_duration) = approle_login(&vault.login_url(), "sid", "rid")
+            .await
+            .unwrap();
+        assert_eq!(lease_duration, 1800);
+
+        vault.script(
+            LOGIN,
+            [Reply::error(
+                StatusCode::BAD_REQUEST,
+                "invalid role or secret ID",
+            )],
+        );
+        let err = approle_login(&vault.login_url(), "sid", "rid")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid role or secret ID"));
+    }
+}
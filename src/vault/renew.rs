@@ -1,11 +1,17 @@
-use crate::{cli::globals::GlobalArgs, vault};
+use crate::{
+    cli::globals::GlobalArgs,
+    vault::{
+        self,
+        status::{Failure, Status},
+    },
+};
 use anyhow::{anyhow, Result};
 use rand::{rngs::StdRng, Rng, SeedableRng};
-use reqwest::Client;
 use secrecy::{ExposeSecret, SecretString};
 use serde_json::{json, Value};
+use std::sync::Arc;
 use tokio::{
-    sync::mpsc,
+    sync::{mpsc, watch},
     time::{sleep, Duration},
 };
 use tracing::{debug, error, instrument, warn};
@@ -13,9 +19,7 @@ use tracing::{debug, error, instrument, warn};
 /// Renew a Vault token
 #[instrument]
 async fn renew_token(url: &str, token: &SecretString, increment: Option<u64>) -> Result<u64> {
-    let client = Client::builder()
-        .user_agent(vault::APP_USER_AGENT)
-        .build()?;
+    let client = vault::client()?;
 
     let payload = json!({
         "increment": increment.map_or(0, |increment| increment)
@@ -57,9 +61,7 @@ async fn renew_db_token(
     lease_id: &str,
     increment: u64,
 ) -> Result<u64> {
-    let client = Client::builder()
-        .user_agent(vault::APP_USER_AGENT)
-        .build()?;
+    let client = vault::client()?;
 
     let payload = json!({
         "increment": increment,
@@ -96,15 +98,34 @@ async fn renew_db_token(
 }
 
 /// Refresh a Vault token
-#[instrument]
-pub async fn try_renew(globals: &GlobalArgs, tx: mpsc::UnboundedSender<()>) -> Result<()> {
-    // renew the token
+///
+/// When the token is read from a Vault Agent token file the agent renews it,
+/// only the DB lease is renewed using the latest token found in the file.
+/// `db_lease` is the lease to renew, it changes when the DB credentials are
+/// fetched again
+#[instrument(skip(db_lease))]
+pub async fn try_renew(
+    globals: &GlobalArgs,
+    tx: mpsc::UnboundedSender<&'static str>,
+    status: Arc<Status>,
+    db_lease: watch::Receiver<String>,
+) -> Result<()> {
+    let token_rx = globals.vault_token_file.as_ref().map_or_else(
+        || {
+            renew_auth_token(globals, tx.clone(), status.clone());
+
+            watch::channel(globals.vault_token.clone()).1
+        },
+        |path| vault::token_file::watch(path.clone(), globals.vault_token.clone()),
+    );
+
+    // renew the DB lease_id
     tokio::spawn({
         let mut rng = StdRng::from_entropy();
         let mut jittered_lease_duration: Duration = Duration::default();
 
         let url = globals.vault_url.clone();
-        let token = globals.vault_token.clone();
+        let db_lease_duration = globals.vault_db_lease_duration;
         let tx = tx.clone();
 
         async move {
@@ -117,8 +138,13 @@ pub async fn try_renew(globals: &GlobalArgs, tx: mpsc::UnboundedSender<()>) -> R
                         sleep(Duration::from_secs(backoff_time)).await;
                     }
 
-                    match renew_token(&url, &token, None).await {
+                    let token = token_rx.borrow().clone();
+                    let db_lease_id = db_lease.borrow().clone();
+
+                    match renew_db_token(&url, &token, &db_lease_id, db_lease_duration).await {
                         Ok(lease_duration) => {
+                            status.db_lease_renewed(lease_duration);
+
                             let factor = rng.gen_range(70..90);
 
                             jittered_lease_duration =
@@ -128,11 +154,13 @@ pub async fn try_renew(globals: &GlobalArgs, tx: mpsc::UnboundedSender<()>) -> R
                         }
 
                         Err(e) => {
-                            error!("Failed to renew token: {}", e);
+                            error!("Failed to renew DB lease: {}", e);
+
+                            status.renewal_failed(Failure::DbLeaseRenewal);
 
                             if attempt == 3 {
-                                error!("Failed to renew token after 3 attempts: {}", e);
-                                let _ = tx.send(());
+                                error!("Failed to renew DB lease after 3 attempts: {}", e);
+                                let _ = tx.send("Vault renewal failed");
                                 return;
                             }
 
@@ -142,7 +170,7 @@ pub async fn try_renew(globals: &GlobalArgs, tx: mpsc::UnboundedSender<()>) -> R
                 }
 
                 debug!(
-                    "Will renew token in {} seconds",
+                    "Will renew DB lease in {} seconds",
                     jittered_lease_duration.as_secs()
                 );
 
@@ -151,16 +179,21 @@ pub async fn try_renew(globals: &GlobalArgs, tx: mpsc::UnboundedSender<()>) -> R
         }
     });
 
-    // renew the DB lease_id
+    Ok(())
+}
+
+/// Renew the token obtained with AppRole
+fn renew_auth_token(
+    globals: &GlobalArgs,
+    tx: mpsc::UnboundedSender<&'static str>,
+    status: Arc<Status>,
+) {
     tokio::spawn({
         let mut rng = StdRng::from_entropy();
         let mut jittered_lease_duration: Duration = Duration::default();
 
         let url = globals.vault_url.clone();
         let token = globals.vault_token.clone();
-        let db_lease_id = globals.vault_db_lease_id.clone();
-        let db_lease_duration = globals.vault_db_lease_duration;
-        let tx = tx.clone();
 
         async move {
             loop {
@@ -172,8 +205,10 @@ pub async fn try_renew(globals: &GlobalArgs, tx: mpsc::UnboundedSender<()>) -> R
                         sleep(Duration::from_secs(backoff_time)).await;
                     }
 
-                    match renew_db_token(&url, &token, &db_lease_id, db_lease_duration).await {
+                    match renew_token(&url, &token, None).await {
                         Ok(lease_duration) => {
+                            status.token_renewed(lease_duration);
+
                             let factor = rng.gen_range(70..90);
 
                             jittered_lease_duration =
@@ -183,11 +218,14 @@ pub async fn try_renew(globals: &GlobalArgs, tx: mpsc::UnboundedSender<()>) -> R
                         }
 
                         Err(e) => {
-                            error!("Failed to renew DB lease: {}", e);
+                            error!("Failed to renew token: {}", e);
+
+                            status.renewal_failed(Failure::TokenRenewal);
 
                             if attempt == 3 {
-                                error!("Failed to renew DB lease after 3 attempts: {}", e);
-                                let _ = tx.send(());
+                                error!("Failed to renew token after 3 attempts: {}", e);
+                                status.token_invalid();
+                                let _ = tx.send("Vault renewal failed");
                                 return;
                             }
 
@@ -197,7 +235,7 @@ pub async fn try_renew(globals: &GlobalArgs, tx: mpsc::UnboundedSender<()>) -> R
                 }
 
                 debug!(
-                    "Will renew DB lease in {} seconds",
+                    "Will renew token in {} seconds",
                     jittered_lease_duration.as_secs()
                 );
 
@@ -205,6 +243,154 @@ pub async fn try_renew(globals: &GlobalArgs, tx: mpsc::UnboundedSender<()>) -> R
             }
         }
     });
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::mock::{MockVault, Reply, LEASE_ID, RENEW_LEASE, RENEW_SELF, TOKEN};
+    use reqwest::StatusCode;
+    use tokio::time::timeout;
+
+    fn globals(vault: &MockVault) -> GlobalArgs {
+        let mut globals = GlobalArgs::new(vault.login_url());
+        globals.set_token(SecretString::from(TOKEN));
+        globals.vault_db_lease_id = LEASE_ID.to_string();
+        globals.vault_db_lease_duration = 3600;
+        globals
+    }
+
+    // wait for the renewal tasks
+    async fn eventually(f: impl Fn() -> bool) {
+        timeout(Duration::from_secs(5), async {
+            while !f() {
+                sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("condition not met in time");
+    }
+
+    #[tokio::test]
+    async fn test_try_renew() {
+        let vault = MockVault::start().await;
+
+        // renewed again in less than 2 seconds
+        vault.script(RENEW_LEASE, [Reply::lease(2)]);
+
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let status = Arc::new(Status::new(3600));
+        let (db_lease, db_lease_rx) = watch::channel(LEASE_ID.to_string());
+
+        try_renew(&globals(&vault), tx, status.clone(), db_lease_rx)
+            .await
+            .unwrap();
+
+        eventually(|| !vault.calls(RENEW_LEASE).is_empty()).await;
+
+        let call = &vault.calls(RENEW_LEASE)[0];
+        assert_eq!(call.token.as_deref(), Some(TOKEN));
+        assert_eq!(call.body["lease_id"], LEASE_ID);
+        assert_eq!(call.body["increment"], 3600);
+
+        // the call is recorded before genesis reads the reply
+        eventually(|| status.snapshot().db_lease_ttl().is_some_and(|ttl| ttl <= 2)).await;
+
+        eventually(|| status.snapshot().token_ttl().is_some()).await;
+        assert_eq!(vault.calls(RENEW_SELF)[0].body["increment"], 0);
+
+        // the supervisor fetched new credentials
+        db_lease
+            .send("database/creds/genesis/new".to_string())
+            .unwrap();
+
+        eventually(|| {
+            vault
+                .calls(RENEW_LEASE)
+                .iter()
+                .any(|call| call.body["lease_id"] == "database/creds/genesis/new")
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_try_renew_db_lease_fails() {
+        let vault = MockVault::start().await;
+
+        vault.script(
+            RENEW_LEASE,
+            [Reply::error(StatusCode::BAD_REQUEST, "lease not found")],
+        );
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let status = Arc::new(Status::new(3600));
+        let (_db_lease, db_lease_rx) = watch::channel(LEASE_ID.to_string());
+
+        try_renew(&globals(&vault), tx, status.clone(), db_lease_rx)
+            .await
+            .unwrap();
+
+        // 3 attempts, 2 and 4 seconds apart, then the shutdown
+        let reason = timeout(Duration::from_secs(10), rx.recv()).await.unwrap();
+        assert_eq!(reason, Some("Vault renewal failed"));
+        assert_eq!(vault.calls(RENEW_LEASE).len(), 3);
+
+        let snapshot = status.snapshot();
+        assert_eq!(snapshot.last_error, Some(Failure::DbLeaseRenewal));
+        assert!(snapshot.token_valid);
+    }
+
+    #[tokio::test]
+    async fn test_try_renew_token_fails() {
+        let vault = MockVault::start().await;
+
+        vault.script(
+            RENEW_SELF,
+            [Reply::error(StatusCode::FORBIDDEN, "permission denied")],
+        );
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let status = Arc::new(Status::new(3600));
+        let (_db_lease, db_lease_rx) = watch::channel(LEASE_ID.to_string());
+
+        try_renew(&globals(&vault), tx, status.clone(), db_lease_rx)
+            .await
+            .unwrap();
+
+        let reason = timeout(Duration::from_secs(10), rx.recv()).await.unwrap();
+        assert_eq!(reason, Some("Vault renewal failed"));
+        assert_eq!(vault.calls(RENEW_SELF).len(), 3);
+        assert!(!status.snapshot().token_valid);
+    }
+
+    #[tokio::test]
+    async fn test_try_renew_token_file() {
+        let vault = MockVault::start().await;
+
+        let path = std::env::temp_dir().join(format!("genesis-token-{}", ulid::Ulid::new()));
+        std::fs::write(&path, "hvs.agent\n").unwrap();
+
+        let globals = GlobalArgs {
+            vault_token: SecretString::from("hvs.agent"),
+            vault_token_file: Some(path.clone()),
+            ..globals(&vault)
+        };
+
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let (_db_lease, db_lease_rx) = watch::channel(LEASE_ID.to_string());
+
+        try_renew(&globals, tx, Arc::new(Status::new(3600)), db_lease_rx)
+            .await
+            .unwrap();
+
+        eventually(|| !vault.calls(RENEW_LEASE).is_empty()).await;
+        std::fs::remove_file(&path).unwrap();
+
+        // the agent renews its token
+        assert_eq!(
+            vault.calls(RENEW_LEASE)[0].token.as_deref(),
+            Some("hvs.agent")
+        );
+        assert!(vault.calls(RENEW_SELF).is_empty());
+    }
 }
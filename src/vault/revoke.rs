@@ -0,0 +1,165 @@
+use crate::{cli::globals::GlobalArgs, vault};
+use anyhow::{anyhow, Result};
+use secrecy::{ExposeSecret, SecretString};
+use serde_json::{json, Value};
+use tracing::{error, info, instrument};
+
+/// Revoke a lease, used to drop the DB credentials on shutdown or once they
+/// are replaced
+#[instrument]
+pub(crate) async fn revoke_lease(url: &str, token: &SecretString, lease_id: &str) -> Result<()> {
+    let client = vault::client()?;
+
+    let payload = json!({
+        "lease_id": lease_id
+    });
+
+    // Parse the URL
+    let revoke_url = vault::endpoint_url(url, "/v1/sys/leases/revoke")?;
+
+    let response = client
+        .put(&revoke_url)
+        .json(&payload)
+        .header("X-Vault-Token", token.expose_secret())
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let json_response: Value = response.json().await?;
+
+        return Err(anyhow!(
+            "{} - {}, {}",
+            revoke_url,
+            status,
+            json_response["errors"][0].as_str().unwrap_or("")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Revoke the token used to authenticate
+#[instrument]
+async fn revoke_self(url: &str, token: &SecretString) -> Result<()> {
+    let client = vault::client()?;
+
+    // Parse the URL
+    let revoke_url = vault::endpoint_url(url, "/v1/auth/token/revoke-self")?;
+
+    let response = client
+        .post(&revoke_url)
+        .header("X-Vault-Token", token.expose_secret())
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let json_response: Value = response.json().await?;
+
+        return Err(anyhow!(
+            "{} - {}, {}",
+            revoke_url,
+            status,
+            json_response["errors"][0].as_str().unwrap_or("")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Revoke the DB lease and the Vault token, errors are only logged since
+/// this runs while shutting down. A token read from a Vault Agent token file
+/// belongs to the agent and is not revoked.
+#[instrument(skip(globals))]
+pub async fn cleanup(globals: &GlobalArgs) {
+    let token = globals.vault_token_file.as_ref().map_or_else(
+        || globals.vault_token.clone(),
+        |path| {
+            vault::token_file::read(path).unwrap_or_else(|e| {
+                error!("Failed to re-read Vault token file: {}", e);
+                globals.vault_token.clone()
+            })
+        },
+    );
+
+    if !globals.vault_db_lease_id.is_empty() {
+        match revoke_lease(&globals.vault_url, &token, &globals.vault_db_lease_id).await {
+            Ok(()) => info!("Revoked DB lease"),
+            Err(e) => error!("Failed to revoke DB lease: {}", e),
+        }
+    }
+
+    if globals.vault_token_file.is_some() {
+        return;
+    }
+
+    match revoke_self(&globals.vault_url, &globals.vault_token).await {
+        Ok(()) => info!("Revoked Vault token"),
+        Err(e) => error!("Failed to revoke Vault token: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vault::mock::{MockVault, Reply, LEASE_ID, REVOKE_LEASE, REVOKE_SELF, TOKEN};
+    use reqwest::{Method, StatusCode};
+
+    fn globals(vault: &MockVault) -> GlobalArgs {
+        let mut globals = GlobalArgs::new(vault.login_url());
+        globals.set_token(SecretString::from(TOKEN));
+        globals.vault_db_lease_id = LEASE_ID.to_string();
+        globals
+    }
+
+    #[tokio::test]
+    async fn test_cleanup() {
+        let vault = MockVault::start().await;
+
+        cleanup(&globals(&vault)).await;
+
+        let revoked = vault.calls(REVOKE_LEASE);
+        assert_eq!(revoked.len(), 1);
+        assert_eq!(revoked[0].method, Method::PUT);
+        assert_eq!(revoked[0].body["lease_id"], LEASE_ID);
+        assert_eq!(vault.calls(REVOKE_SELF)[0].token.as_deref(), Some(TOKEN));
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_errors() {
+        let vault = MockVault::start().await;
+
+        // the token is still revoked when the lease can't be
+        vault.script(
+            REVOKE_LEASE,
+            [Reply::error(StatusCode::BAD_REQUEST, "invalid lease")],
+        );
+
+        cleanup(&globals(&vault)).await;
+        assert_eq!(vault.calls(REVOKE_SELF).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_token_file() {
+        let vault = MockVault::start().await;
+
+        let path = std::env::temp_dir().join(format!("genesis-token-{}", ulid::Ulid::new()));
+        std::fs::write(&path, "hvs.agent\n").unwrap();
+
+        let globals = GlobalArgs {
+            vault_token_file: Some(path.clone()),
+            ..globals(&vault)
+        };
+
+        cleanup(&globals).await;
+        std::fs::remove_file(&path).unwrap();
+
+        // the lease is revoked with the latest token, the token is the agent's
+        assert_eq!(
+            vault.calls(REVOKE_LEASE)[0].token.as_deref(),
+            Some("hvs.agent")
+        );
+        assert!(vault.calls(REVOKE_SELF).is_empty());
+    }
+}
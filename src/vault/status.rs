@@ -0,0 +1,164 @@
+use chrono::{DateTime, Duration, Utc};
+use std::sync::RwLock;
+
+/// Remaining seconds below which the credentials are reported as expiring
+pub const EXPIRING_THRESHOLD: i64 = 60;
+
+/// What failed, the error itself is only logged since `/health` is public
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    TokenRenewal,
+    DbLeaseRenewal,
+}
+
+impl Failure {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::TokenRenewal => "token_renewal_failed",
+            Self::DbLeaseRenewal => "db_lease_renewal_failed",
+        }
+    }
+}
+
+/// State of the Vault token and DB lease as seen by the renewal tasks
+#[derive(Debug, Default, Clone)]
+pub struct Snapshot {
+    pub enabled: bool,
+    pub token_valid: bool,
+    pub token_expires_at: Option<DateTime<Utc>>,
+    pub db_lease_expires_at: Option<DateTime<Utc>>,
+    pub last_renewal: Option<DateTime<Utc>>,
+    pub last_error: Option<Failure>,
+}
+
+impl Snapshot {
+    /// Seconds left on the Vault token, `None` if unknown (e.g. managed by a Vault Agent)
+    #[must_use]
+    pub fn token_ttl(&self) -> Option<i64> {
+        self.token_expires_at.map(seconds_left)
+    }
+
+    /// Seconds left on the DB lease
+    #[must_use]
+    pub fn db_lease_ttl(&self) -> Option<i64> {
+        self.db_lease_expires_at.map(seconds_left)
+    }
+}
+
+fn seconds_left(expires_at: DateTime<Utc>) -> i64 {
+    (expires_at - Utc::now()).num_seconds().max(0)
+}
+
+fn expires_at(lease_duration: u64) -> DateTime<Utc> {
+    Utc::now() + Duration::seconds(i64::try_from(lease_duration).unwrap_or(i64::MAX / 1000))
+}
+
+/// Shared Vault status, updated by `vault::renew` and read by the health checks
+#[derive(Debug, Default)]
+pub struct Status(RwLock<Snapshot>);
+
+impl Status {
+    /// Vault is in use, the token is valid and the DB lease has `db_lease_duration` seconds
+    #[must_use]
+    pub fn new(db_lease_duration: u64) -> Self {
+        Self(RwLock::new(Snapshot {
+            enabled: true,
+            token_valid: true,
+            db_lease_expires_at: Some(expires_at(db_lease_duration)),
+            ..Snapshot::default()
+        }))
+    }
+
+    /// Vault is not used (dev mode)
+    #[must_use]
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Snapshot)) {
+        if let Ok(mut snapshot) = self.0.write() {
+            f(&mut snapshot);
+        }
+    }
+
+    pub fn token_renewed(&self, lease_duration: u64) {
+        self.update(|s| {
+            s.token_valid = true;
+            s.token_expires_at = Some(expires_at(lease_duration));
+            s.last_renewal = Some(Utc::now());
+            s.last_error = s.last_error.filter(|f| *f != Failure::TokenRenewal);
+        });
+    }
+
+    /// The DB lease was renewed, says nothing about the token
+    pub fn db_lease_renewed(&self, lease_duration: u64) {
+        self.update(|s| {
+            s.db_lease_expires_at = Some(expires_at(lease_duration));
+            s.last_renewal = Some(Utc::now());
+            s.last_error = s.last_error.filter(|f| *f != Failure::DbLeaseRenewal);
+        });
+    }
+
+    pub fn renewal_failed(&self, failure: Failure) {
+        self.update(|s| s.last_error = Some(failure));
+    }
+
+    /// The renewal gave up, the token can no longer be trusted
+    pub fn token_invalid(&self) {
+        self.update(|s| {
+            s.token_valid = false;
+            s.last_error = Some(Failure::TokenRenewal);
+        });
+    }
+
+    #[must_use]
+    pub fn snapshot(&self) -> Snapshot {
+        self.0
+            .read()
+            .map(|snapshot| snapshot.clone())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let status = Status::new(3600);
+        let snapshot = status.snapshot();
+        assert!(snapshot.enabled);
+        assert!(snapshot.token_valid);
+        assert!(snapshot.token_ttl().is_none());
+        assert!(snapshot.db_lease_ttl().unwrap() > 3590);
+
+        status.renewal_failed(Failure::TokenRenewal);
+        assert_eq!(status.snapshot().last_error, Some(Failure::TokenRenewal));
+
+        // only the token renewal clears its failure
+        status.db_lease_renewed(3600);
+        assert_eq!(status.snapshot().last_error, Some(Failure::TokenRenewal));
+
+        status.token_renewed(60);
+        let snapshot = status.snapshot();
+        assert!(snapshot.last_error.is_none());
+        assert!(snapshot.last_renewal.is_some());
+        assert!(snapshot.token_ttl().unwrap() <= 60);
+
+        status.token_invalid();
+        assert!(!status.snapshot().token_valid);
+
+        // the DB lease doesn't make the token valid again
+        status.db_lease_renewed(3600);
+        assert!(!status.snapshot().token_valid);
+    }
+
+    #[test]
+    fn test_status_disabled() {
+        let snapshot = Status::disabled().snapshot();
+        assert!(!snapshot.enabled);
+        assert!(snapshot.db_lease_ttl().is_none());
+    }
+}
@@ -0,0 +1,95 @@
+use anyhow::{anyhow, Context, Result};
+use secrecy::{ExposeSecret, SecretString};
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::{
+    sync::watch,
+    time::{sleep, Duration},
+};
+use tracing::{debug, error, info, instrument};
+
+/// How often the token file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Read a Vault token written by a Vault Agent sink
+/// # Errors
+/// Will return an error if the file can't be read or is empty
+pub fn read(path: &Path) -> Result<SecretString> {
+    let token = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read Vault token file {}", path.display()))?;
+
+    let token = token.trim();
+
+    if token.is_empty() {
+        return Err(anyhow!("Vault token file {} is empty", path.display()));
+    }
+
+    Ok(SecretString::from(token.to_string()))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Re-read the token file when it changes, the agent takes care of renewing
+/// the token so only the latest value needs to be tracked
+#[instrument(skip(token))]
+pub fn watch(path: PathBuf, token: SecretString) -> watch::Receiver<SecretString> {
+    let (tx, rx) = watch::channel(token);
+
+    tokio::spawn(async move {
+        let mut last_modified = modified(&path);
+
+        loop {
+            sleep(POLL_INTERVAL).await;
+
+            let current = modified(&path);
+
+            if current == last_modified {
+                continue;
+            }
+
+            last_modified = current;
+
+            match read(&path) {
+                Ok(token) => {
+                    let changed = tx.borrow().expose_secret() != token.expose_secret();
+
+                    if changed {
+                        info!("Vault token file changed, using new token");
+
+                        if tx.send(token).is_err() {
+                            debug!("No token receivers left, stop watching");
+                            return;
+                        }
+                    }
+                }
+
+                Err(e) => error!("Failed to re-read Vault token file: {}", e),
+            }
+        }
+    });
+
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read() {
+        let path = std::env::temp_dir().join(format!("genesis-token-{}", ulid::Ulid::new()));
+
+        std::fs::write(&path, "hvs.token\n").unwrap();
+        assert_eq!(read(&path).unwrap().expose_secret(), "hvs.token");
+
+        std::fs::write(&path, " \n").unwrap();
+        assert!(read(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(read(&path).is_err());
+    }
+}